@@ -0,0 +1,146 @@
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum BidIncrement {
+    Bps(u16),       // share of the current bid
+    Absolute(U128), // fixed amount on top of the current bid
+}
+
+/// seller chosen rules of an english auction, unset fields keep the marketplace defaults
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", default)]
+pub struct AuctionRules {
+    pub min_increment: BidIncrement,
+    /// a bid this close (nanoseconds) to ended_at extends the auction
+    pub extension_window: U64,
+    /// the auction then runs at least this long (nanoseconds) after the bid
+    pub extension_length: U64,
+    /// extensions never push ended_at past this
+    pub max_ended_at: Option<U64>,
+}
+
+impl Default for AuctionRules {
+    fn default() -> Self {
+        Self {
+            min_increment: BidIncrement::Bps(1_000),
+            extension_window: U64(FIVE_MINUTES),
+            extension_length: U64(FIVE_MINUTES),
+            max_ended_at: None,
+        }
+    }
+}
+
+impl AuctionRules {
+    pub fn assert_valid(&self, ended_at: u64) {
+        match &self.min_increment {
+            BidIncrement::Bps(bps) => assert!(
+                *bps > 0 && *bps <= 10_000,
+                "DS: min_increment bps must be between 1 and 10000"
+            ),
+            BidIncrement::Absolute(amount) => assert!(
+                amount.0 > 0,
+                "DS: min_increment must be greater than 0"
+            ),
+        }
+        if let Some(max_ended_at) = self.max_ended_at {
+            assert!(
+                max_ended_at.0 >= ended_at,
+                "DS: max_ended_at is before ended_at"
+            );
+        }
+    }
+
+    /// least amount that outbids `current_bid`
+    pub fn min_next_bid(&self, current_bid: u128) -> u128 {
+        let increment = match &self.min_increment {
+            BidIncrement::Bps(bps) => mul_div(current_bid, *bps as u128, 10_000),
+            BidIncrement::Absolute(amount) => amount.0,
+        };
+        // always strictly above the current bid, even when the increment rounds down to 0
+        current_bid + increment.max(1)
+    }
+
+    /// ended_at after a bid placed at `current_time`, never earlier than before
+    pub fn extended_ended_at(&self, ended_at: u64, current_time: u64) -> u64 {
+        if ended_at.saturating_sub(current_time) > self.extension_window.0 {
+            return ended_at;
+        }
+        let extended_ended_at = current_time + self.extension_length.0;
+        let extended_ended_at = match self.max_ended_at {
+            Some(max_ended_at) => extended_ended_at.min(max_ended_at.0),
+            None => extended_ended_at,
+        };
+        extended_ended_at.max(ended_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDED_AT: u64 = 10 * FIVE_MINUTES;
+
+    fn rules(max_ended_at: Option<u64>) -> AuctionRules {
+        AuctionRules {
+            max_ended_at: max_ended_at.map(U64),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn early_bids_keep_ended_at() {
+        let ended_at = rules(None).extended_ended_at(ENDED_AT, ENDED_AT - FIVE_MINUTES - 1);
+        assert_eq!(ended_at, ENDED_AT);
+    }
+
+    #[test]
+    fn late_bids_extend_ended_at() {
+        let ended_at = rules(None).extended_ended_at(ENDED_AT, ENDED_AT - 10);
+        assert_eq!(ended_at, ENDED_AT - 10 + FIVE_MINUTES);
+    }
+
+    #[test]
+    fn late_bids_never_shorten_ended_at() {
+        let rules = AuctionRules {
+            extension_window: U64(FIVE_MINUTES),
+            extension_length: U64(60),
+            ..Default::default()
+        };
+        assert_eq!(rules.extended_ended_at(ENDED_AT, ENDED_AT - FIVE_MINUTES), ENDED_AT);
+        assert_eq!(rules.extended_ended_at(ENDED_AT, ENDED_AT - 10), ENDED_AT + 50);
+    }
+
+    #[test]
+    fn extensions_are_capped_by_max_ended_at() {
+        let max_ended_at = ENDED_AT + 100;
+        assert_eq!(rules(Some(max_ended_at)).extended_ended_at(ENDED_AT, ENDED_AT - 10), max_ended_at);
+        // a cap at ended_at stops extensions without moving ended_at back
+        assert_eq!(rules(Some(ENDED_AT)).extended_ended_at(ENDED_AT, ENDED_AT - 10), ENDED_AT);
+    }
+
+    #[test]
+    fn bps_increment_is_a_share_of_the_current_bid() {
+        let rules = rules(None);
+        assert_eq!(rules.min_next_bid(1_000), 1_100);
+        assert_eq!(rules.min_next_bid(u128::MAX / 2), u128::MAX / 2 + u128::MAX / 20);
+        // rounds down to 0 but still outbids
+        assert_eq!(rules.min_next_bid(5), 6);
+    }
+
+    #[test]
+    fn absolute_increment_is_added_to_the_current_bid() {
+        let rules = AuctionRules {
+            min_increment: BidIncrement::Absolute(U128(250)),
+            ..Default::default()
+        };
+        assert_eq!(rules.min_next_bid(0), 250);
+        assert_eq!(rules.min_next_bid(1_000), 1_250);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: max_ended_at is before ended_at")]
+    fn max_ended_at_before_ended_at_is_invalid() {
+        rules(Some(ENDED_AT - 1)).assert_valid(ENDED_AT);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::*;
+
+/// funds an account keeps on the marketplace to bid from, in one currency.
+/// committed is the sum of its open bids drawn on the balance, which may exceed the balance
+/// up to the marketplace's bidding_commitment_bps
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BiddingBalance {
+    pub ft_token_id: Option<AccountId>, // None means NEAR
+    pub balance: U128,
+    pub committed: U128,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[payable]
+    pub fn deposit_bidding_balance(&mut self) {
+        self.internal_deposit_bidding_balance(
+            env::predecessor_account_id(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+        );
+    }
+
+    /// moves `amount` (everything that is not needed, when None) of the bidding balance to pending withdrawals
+    #[payable]
+    pub fn withdraw_bidding_balance(&mut self, ft_token_id: Option<AccountId>, amount: Option<U128>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut bidding_balance = self.internal_bidding_balance(&account_id, &ft_token_id);
+        let available = self.internal_available_bidding_balance(&bidding_balance);
+        let amount = amount.map_or(available, |x| x.0);
+        assert!(amount > 0, "DS: Nothing to withdraw");
+        assert!(
+            amount <= available,
+            "DS: Only {} can be withdrawn while bids are open",
+            available
+        );
+
+        bidding_balance.balance = U128(bidding_balance.balance.0 - amount);
+        self.internal_save_bidding_balance(&account_id, bidding_balance);
+        self.internal_credit_pending(ft_token_id.clone(), account_id.clone(), amount);
+
+        env::log_str(
+            &json!({
+                "type": "withdraw_bidding_balance",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_bidding_balance(&self, account_id: AccountId, ft_token_id: Option<AccountId>) -> BiddingBalance {
+        self.internal_bidding_balance(&account_id, &ft_token_id)
+    }
+
+    pub fn get_bidding_balances(&self, account_id: AccountId) -> Vec<BiddingBalance> {
+        match self.bidding_balances.get(&account_id) {
+            Some(bidding_balances) => bidding_balances.values().collect(),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn internal_deposit_bidding_balance(
+        &mut self,
+        account_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: u128,
+    ) {
+        assert!(amount > 0, "DS: Deposit must be greater than 0");
+        let mut bidding_balance = self.internal_bidding_balance(&account_id, &ft_token_id);
+        bidding_balance.balance = U128(bidding_balance.balance.0 + amount);
+        self.internal_save_bidding_balance(&account_id, bidding_balance);
+
+        env::log_str(
+            &json!({
+                "type": "deposit_bidding_balance",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// reserves `amount` of the bidding balance for a new bid
+    pub(crate) fn internal_commit_bidding_balance(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+        amount: u128,
+    ) {
+        let mut bidding_balance = self.internal_bidding_balance(account_id, ft_token_id);
+        let committed = bidding_balance.committed.0 + amount;
+        let max_committed =
+            bidding_balance.balance.0.saturating_mul(self.bidding_commitment_bps as u128) / 10_000;
+        assert!(
+            committed <= max_committed,
+            "DS: Bids would commit {}, the bidding balance allows {}",
+            committed,
+            max_committed
+        );
+        bidding_balance.committed = U128(committed);
+        self.internal_save_bidding_balance(account_id, bidding_balance);
+    }
+
+    pub(crate) fn internal_release_bidding_balance(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+        amount: u128,
+    ) {
+        let mut bidding_balance = self.internal_bidding_balance(account_id, ft_token_id);
+        bidding_balance.committed = U128(bidding_balance.committed.0.saturating_sub(amount));
+        self.internal_save_bidding_balance(account_id, bidding_balance);
+    }
+
+    /// pays a winning bid out of the bidding balance, false when the balance no longer covers it
+    pub(crate) fn internal_charge_bidding_balance(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+        amount: u128,
+    ) -> bool {
+        let mut bidding_balance = self.internal_bidding_balance(account_id, ft_token_id);
+        if bidding_balance.balance.0 < amount {
+            return false;
+        }
+        bidding_balance.balance = U128(bidding_balance.balance.0 - amount);
+        bidding_balance.committed = U128(bidding_balance.committed.0.saturating_sub(amount));
+        self.internal_save_bidding_balance(account_id, bidding_balance);
+        true
+    }
+
+    /// takes `amount` out of the bidding balance into another escrow, open bids keep what they need
+    pub(crate) fn internal_debit_bidding_balance(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+        amount: u128,
+    ) {
+        let mut bidding_balance = self.internal_bidding_balance(account_id, ft_token_id);
+        let available = self.internal_available_bidding_balance(&bidding_balance);
+        assert!(
+            amount <= available,
+            "DS: Only {} of the bidding balance is available",
+            available
+        );
+        bidding_balance.balance = U128(bidding_balance.balance.0 - amount);
+        self.internal_save_bidding_balance(account_id, bidding_balance);
+    }
+
+    /// what is left over once the balance backs its open bids under the commitment policy
+    fn internal_available_bidding_balance(&self, bidding_balance: &BiddingBalance) -> u128 {
+        let required = bidding_balance
+            .committed
+            .0
+            .saturating_mul(10_000)
+            .div_ceil(self.bidding_commitment_bps as u128);
+        bidding_balance.balance.0.saturating_sub(required)
+    }
+
+    fn internal_bidding_balance(&self, account_id: &AccountId, ft_token_id: &Option<AccountId>) -> BiddingBalance {
+        self.bidding_balances
+            .get(account_id)
+            .and_then(|bidding_balances| bidding_balances.get(ft_token_id))
+            .unwrap_or(BiddingBalance {
+                ft_token_id: ft_token_id.clone(),
+                balance: U128(0),
+                committed: U128(0),
+            })
+    }
+
+    fn internal_save_bidding_balance(&mut self, account_id: &AccountId, bidding_balance: BiddingBalance) {
+        let mut bidding_balances = self.bidding_balances.get(account_id).unwrap_or_else(|| {
+            UnorderedMap::new(StorageKey::BiddingBalancesInner {
+                account_id_hash: hash_account_id(account_id),
+            })
+        });
+        if bidding_balance.balance.0 == 0 && bidding_balance.committed.0 == 0 {
+            bidding_balances.remove(&bidding_balance.ft_token_id);
+        } else {
+            bidding_balances.insert(&bidding_balance.ft_token_id.clone(), &bidding_balance);
+        }
+        if bidding_balances.is_empty() {
+            self.bidding_balances.remove(account_id);
+        } else {
+            self.bidding_balances.insert(account_id, &bidding_balances);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::*;
+
+// losing bids are refunded when the auction settles, bound how many a single listing keeps
+pub const MAX_BIDS_PER_LISTING: u64 = 100;
+
+/// bids of a listing sorted by price, every new bid has to beat the top one so prices never repeat
+pub type PriceAndBidderId = (u128, AccountId);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bid {
+    pub bidder_id: AccountId,
+    pub price: U128,
+    pub time: u64,
+    pub from_bidding_balance: bool, // otherwise the price is escrowed with the bid
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// highest bids first
+    pub fn get_bids(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Bid> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let bid_book = match self.bid_book.get(&contract_and_token_id) {
+            Some(bid_book) => bid_book,
+            None => return vec![],
+        };
+        bid_book
+            .iter_rev()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|((_, bidder_id), _)| {
+                self.bids.get(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))
+            })
+            .collect()
+    }
+
+    pub fn get_bid(&self, nft_contract_id: AccountId, token_id: TokenId, bidder_id: AccountId) -> Option<Bid> {
+        self.bids
+            .get(&format!("{}{}{}{}{}", nft_contract_id, DELIMETER, token_id, DELIMETER, bidder_id))
+    }
+
+    pub fn get_supply_bids(&self, nft_contract_id: AccountId, token_id: TokenId) -> U64 {
+        self.internal_bid_count(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .into()
+    }
+
+    pub fn get_supply_bids_by_bidder_id(&self, account_id: AccountId) -> U64 {
+        self.bids_by_bidder_id
+            .get(&account_id)
+            .map_or(0, |contract_and_token_ids| contract_and_token_ids.len())
+            .into()
+    }
+
+    pub(crate) fn internal_top_bid(&self, contract_and_token_id: &ContractAndTokenId) -> Option<Bid> {
+        let (_, bidder_id) = self.bid_book.get(contract_and_token_id)?.max()?;
+        self.bids
+            .get(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))
+    }
+
+    pub(crate) fn internal_bid_count(&self, contract_and_token_id: &ContractAndTokenId) -> u64 {
+        self.bid_book
+            .get(contract_and_token_id)
+            .map_or(0, |bid_book| bid_book.len())
+    }
+
+    pub(crate) fn internal_insert_bid(&mut self, contract_and_token_id: &ContractAndTokenId, bid: &Bid) {
+        self.bids.insert(
+            &format!("{}{}{}", contract_and_token_id, DELIMETER, bid.bidder_id),
+            bid,
+        );
+
+        let mut bid_book = self.bid_book.get(contract_and_token_id).unwrap_or_else(|| {
+            TreeMap::new(StorageKey::BidBookInner {
+                contract_and_token_id_hash: env::sha256_array(contract_and_token_id.as_bytes()),
+            })
+        });
+        bid_book.insert(&(bid.price.0, bid.bidder_id.clone()), &());
+        self.bid_book.insert(contract_and_token_id, &bid_book);
+
+        let mut contract_and_token_ids = self
+            .bids_by_bidder_id
+            .get(&bid.bidder_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::BidsByBidderIdInner {
+                    account_id_hash: hash_account_id(&bid.bidder_id),
+                })
+            });
+        contract_and_token_ids.insert(contract_and_token_id);
+        self.bids_by_bidder_id.insert(&bid.bidder_id, &contract_and_token_ids);
+    }
+
+    pub(crate) fn internal_remove_bid(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        bidder_id: &AccountId,
+    ) -> Option<Bid> {
+        let bid = self
+            .bids
+            .remove(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))?;
+
+        if let Some(mut bid_book) = self.bid_book.get(contract_and_token_id) {
+            bid_book.remove(&(bid.price.0, bidder_id.clone()));
+            if bid_book.is_empty() {
+                self.bid_book.remove(contract_and_token_id);
+            } else {
+                self.bid_book.insert(contract_and_token_id, &bid_book);
+            }
+        }
+        if let Some(mut contract_and_token_ids) = self.bids_by_bidder_id.get(bidder_id) {
+            contract_and_token_ids.remove(contract_and_token_id);
+            if contract_and_token_ids.is_empty() {
+                self.bids_by_bidder_id.remove(bidder_id);
+            } else {
+                self.bids_by_bidder_id.insert(bidder_id, &contract_and_token_ids);
+            }
+        }
+        Some(bid)
+    }
+
+    /// lowest bid of the listing, the one given up when it holds MAX_BIDS_PER_LISTING bids
+    pub(crate) fn internal_lowest_bid(&self, contract_and_token_id: &ContractAndTokenId) -> Option<Bid> {
+        let (_, bidder_id) = self.bid_book.get(contract_and_token_id)?.min()?;
+        self.bids
+            .get(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))
+    }
+
+    /// takes the highest bid of at least `min_price` that can still be paid, charging its bidding balance.
+    /// Bids drawn on a bidding balance that no longer covers them are dropped for the next highest one
+    pub(crate) fn internal_pop_funded_bid(&mut self, market_data: &MarketData, min_price: u128) -> Option<Bid> {
+        let contract_and_token_id =
+            format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id);
+        loop {
+            let bid = self.internal_top_bid(&contract_and_token_id)?;
+            if bid.price.0 < min_price {
+                return None;
+            }
+            self.internal_remove_bid(&contract_and_token_id, &bid.bidder_id);
+            if !bid.from_bidding_balance
+                || self.internal_charge_bidding_balance(&bid.bidder_id, &market_data.ft_token_id, bid.price.0)
+            {
+                return Some(bid);
+            }
+            self.internal_release_bid(&market_data.ft_token_id, &bid);
+
+            env::log_str(
+                &json!({
+                    "type": "invalidate_bid",
+                    "params": {
+                        "bidder_id": bid.bidder_id,
+                        "nft_contract_id": market_data.nft_contract_id,
+                        "token_id": market_data.token_id,
+                        "amount": bid.price,
+                    }
+                })
+                .to_string(),
+            );
+        }
+    }
+
+    /// gives a removed bid back, escrowed bids to pending withdrawals and the rest to the bidding balance
+    pub(crate) fn internal_release_bid(&mut self, ft_token_id: &Option<AccountId>, bid: &Bid) {
+        if bid.from_bidding_balance {
+            self.internal_release_bidding_balance(&bid.bidder_id, ft_token_id, bid.price.0);
+        } else {
+            self.internal_credit_pending(ft_token_id.clone(), bid.bidder_id.clone(), bid.price.0);
+        }
+    }
+
+    pub(crate) fn internal_remove_bids(&mut self, contract_and_token_id: &ContractAndTokenId) -> Vec<Bid> {
+        let bidder_ids: Vec<AccountId> = match self.bid_book.get(contract_and_token_id) {
+            Some(bid_book) => bid_book.iter().map(|((_, bidder_id), _)| bidder_id).collect(),
+            None => return vec![],
+        };
+        bidder_ids
+            .iter()
+            .filter_map(|bidder_id| self.internal_remove_bid(contract_and_token_id, bidder_id))
+            .collect()
+    }
+}
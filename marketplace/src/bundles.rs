@@ -0,0 +1,551 @@
+use crate::*;
+
+// every token of a bundle is transferred in the same purchase, bound them to what fits in its gas
+pub const MAX_BUNDLE_ITEMS: usize = 5;
+
+const GAS_FOR_RESOLVE_BUNDLE_PURCHASE: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE_BUNDLE_ITEM: Gas = Gas::from_tgas(20);
+const GAS_FOR_RESOLVE_BUNDLE_APPROVALS: Gas = Gas::from_tgas(10);
+// scheduling the transfer of one token once every approval checked out
+const GAS_FOR_BUNDLE_TRANSFER_DISPATCH: Gas = Gas::from_tgas(3);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleItemArgs {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub weight: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleItem {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub weight: u32, // share of the bundle price paid out for this token
+    pub approval_id: Option<U64>, // set once the token is approved with market_type "bundle"
+}
+
+/// several tokens sold together for one price, the bundle can be bought once every token is approved
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bundle {
+    pub bundle_id: U64,
+    pub owner_id: AccountId,
+    pub items: Vec<BundleItem>,
+    pub price: U128,
+    pub ft_token_id: Option<AccountId>, // None means priced in NEAR
+}
+
+impl Bundle {
+    pub fn is_approved(&self) -> bool {
+        self.items.iter().all(|item| item.approval_id.is_some())
+    }
+
+    /// the price split by weight, rounding leftovers go to the last token
+    pub fn item_prices(&self) -> Vec<u128> {
+        let total_weight: u128 = self.items.iter().map(|item| item.weight as u128).sum();
+        let mut item_prices: Vec<u128> = self
+            .items
+            .iter()
+            .map(|item| mul_div(self.price.0, item.weight as u128, total_weight))
+            .collect();
+        let allotted: u128 = item_prices.iter().sum();
+        if let Some(last) = item_prices.last_mut() {
+            *last += self.price.0 - allotted;
+        }
+        item_prices
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// lists the tokens as one bundle, each of them then has to be approved with
+    /// `{"market_type": "bundle", "bundle_id": ..}` before the bundle can be bought
+    #[payable]
+    pub fn add_bundle(&mut self, items: Vec<BundleItemArgs>, price: U128, ft_token_id: Option<AccountId>) -> U64 {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        assert!(
+            items.len() >= 2 && items.len() <= MAX_BUNDLE_ITEMS,
+            "DS: A bundle holds 2 to {} tokens",
+            MAX_BUNDLE_ITEMS
+        );
+        assert!(price.0 > 0, "DS: Bundle price must be greater than 0");
+        if let Some(ft_token_id) = &ft_token_id {
+            assert!(
+                self.approved_ft_token_ids.contains(ft_token_id),
+                "DS: ft_token_id is not approved"
+            );
+        }
+        for (i, item) in items.iter().enumerate() {
+            assert!(
+                self.approved_nft_contract_ids.contains(&item.nft_contract_id),
+                "DS: nft_contract_id is not approved"
+            );
+            assert!(item.weight > 0, "DS: Weight must be greater than 0");
+            assert!(
+                !items[..i]
+                    .iter()
+                    .any(|other| other.nft_contract_id == item.nft_contract_id && other.token_id == item.token_id),
+                "DS: Token is in the bundle twice"
+            );
+            self.internal_assert_not_bundled(&item.nft_contract_id, &item.token_id);
+            assert!(
+                self.market
+                    .get(&format!("{}{}{}", item.nft_contract_id, DELIMETER, item.token_id))
+                    .is_none(),
+                "DS: Token is on sale, delete its listing first"
+            );
+        }
+
+        let storage_amount = self.storage_minimum_balance().0;
+        let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
+        let owner_storage_required =
+            (self.internal_storage_count(&owner_id) + 1) as u128 * storage_amount;
+        assert!(
+            owner_paid_storage >= owner_storage_required,
+            "DS: Insufficient storage paid: {}, for {} entries at {} rate of per entry",
+            owner_paid_storage,
+            owner_storage_required / storage_amount,
+            storage_amount
+        );
+
+        let bundle_id = self.next_bundle_id;
+        self.next_bundle_id += 1;
+        let bundle = Bundle {
+            bundle_id: bundle_id.into(),
+            owner_id: owner_id.clone(),
+            items: items
+                .into_iter()
+                .map(|item| BundleItem {
+                    nft_contract_id: item.nft_contract_id,
+                    token_id: item.token_id,
+                    weight: item.weight,
+                    approval_id: None,
+                })
+                .collect(),
+            price,
+            ft_token_id,
+        };
+        self.bundles.insert(&bundle_id, &bundle);
+        for item in bundle.items.iter() {
+            self.bundled_tokens
+                .insert(&format!("{}{}{}", item.nft_contract_id, DELIMETER, item.token_id), &bundle_id);
+        }
+
+        let mut bundle_ids = self.bundles_by_owner_id.get(&owner_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::BundlesByOwnerIdInner {
+                account_id_hash: hash_account_id(&owner_id),
+            })
+        });
+        bundle_ids.insert(&bundle_id);
+        self.bundles_by_owner_id.insert(&owner_id, &bundle_ids);
+
+        env::log_str(
+            &json!({
+                "type": "add_bundle",
+                "params": bundle,
+            })
+            .to_string(),
+        );
+        bundle.bundle_id
+    }
+
+    #[payable]
+    pub fn delete_bundle(&mut self, bundle_id: U64) {
+        assert_one_yocto();
+        let bundle = self.bundles.get(&bundle_id.0).expect("DS: Bundle does not exist");
+        assert!(
+            [bundle.owner_id.clone(), self.owner_id.clone()].contains(&env::predecessor_account_id()),
+            "DS: Seller or owner only"
+        );
+        self.internal_delete_bundle(&bundle);
+
+        env::log_str(
+            &json!({
+                "type": "delete_bundle",
+                "params": {
+                    "bundle_id": bundle_id,
+                    "owner_id": bundle.owner_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    #[payable]
+    pub fn buy_bundle(&mut self, bundle_id: U64) {
+        let buyer_id = env::predecessor_account_id();
+        let excess = self.internal_buy_bundle(
+            bundle_id,
+            buyer_id.clone(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+        );
+        if excess > 0 {
+            self.internal_credit_pending(None, buyer_id, excess);
+        }
+    }
+
+    pub fn get_bundle(&self, bundle_id: U64) -> Option<Bundle> {
+        self.bundles.get(&bundle_id.0)
+    }
+
+    pub fn get_bundles_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Bundle> {
+        let bundle_ids = match self.bundles_by_owner_id.get(&account_id) {
+            Some(bundle_ids) => bundle_ids,
+            None => return vec![],
+        };
+        bundle_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|bundle_id| self.bundles.get(&bundle_id))
+            .collect()
+    }
+
+    pub fn get_supply_bundles_by_owner_id(&self, account_id: AccountId) -> U64 {
+        self.bundles_by_owner_id
+            .get(&account_id)
+            .map_or(0, |bundle_ids| bundle_ids.len())
+            .into()
+    }
+
+    /// every token is transferred in the same purchase. When some transfers fail the buyer keeps the tokens
+    /// that moved and pays their item_prices, the price of the others is refunded and reported in
+    /// bundle_purchase_failed. A moved token whose payout is rejected is refunded too
+    #[private]
+    pub fn resolve_bundle_purchase(&mut self, buyer_id: AccountId, bundle: Bundle, item_prices: Vec<U128>) -> U128 {
+        let mut refund = 0;
+        let mut moved_tokens = vec![];
+        let mut failed_tokens = vec![];
+        for (i, (item, item_price)) in bundle.items.iter().zip(&item_prices).enumerate() {
+            let token = json!({
+                "nft_contract_id": item.nft_contract_id,
+                "token_id": item.token_id,
+            });
+            let value = match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => value,
+                _ => {
+                    refund += item_price.0;
+                    failed_tokens.push(token);
+                    continue;
+                }
+            };
+            moved_tokens.push(token);
+            match self.internal_split_payout(&value, item_price.0, &bundle.owner_id) {
+                Some(payout) => {
+                    self.internal_credit_sale(&bundle.ft_token_id, &bundle.owner_id, item_price.0, payout, None)
+                }
+                None => {
+                    refund += item_price.0;
+                    log_payout_rejected(
+                        &bundle.owner_id,
+                        &item.nft_contract_id,
+                        &item.token_id,
+                        &buyer_id,
+                        &bundle.ft_token_id,
+                        item_price.0,
+                    );
+                }
+            }
+        }
+        if refund > 0 {
+            self.internal_credit_pending(bundle.ft_token_id.clone(), buyer_id.clone(), refund);
+        }
+
+        if !failed_tokens.is_empty() {
+            env::log_str(
+                &json!({
+                    "type": "bundle_purchase_failed",
+                    "params": {
+                        "bundle_id": bundle.bundle_id,
+                        "owner_id": bundle.owner_id,
+                        "buyer_id": buyer_id,
+                        "refund": U128(refund),
+                        "ft_token_id": bundle.ft_token_id,
+                        "moved_tokens": moved_tokens,
+                        "failed_tokens": failed_tokens,
+                    }
+                })
+                .to_string(),
+            );
+            return U128(refund);
+        }
+        env::log_str(
+            &json!({
+                "type": "resolve_bundle_purchase",
+                "params": {
+                    "bundle_id": bundle.bundle_id,
+                    "owner_id": bundle.owner_id,
+                    "buyer_id": buyer_id,
+                    "price": bundle.price,
+                    "ft_token_id": bundle.ft_token_id,
+                    "item_prices": item_prices,
+                }
+            })
+            .to_string(),
+        );
+        bundle.price
+    }
+
+    /// transfers every token once all of their approvals checked out. A bundle with a token the marketplace
+    /// is no longer approved for can't be sold, the buyer is refunded in full
+    #[private]
+    pub fn resolve_bundle_approvals(&mut self, buyer_id: AccountId, bundle: Bundle, item_prices: Vec<U128>) {
+        // a contract that can't tell is left to fail the transfer itself
+        let not_approved: Vec<&BundleItem> = bundle
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| approval_check_result_at(*i as u64) == Some(false))
+            .map(|(_, item)| item)
+            .collect();
+        if !not_approved.is_empty() {
+            self.internal_credit_pending(bundle.ft_token_id.clone(), buyer_id.clone(), bundle.price.0);
+            env::log_str(
+                &json!({
+                    "type": "bundle_invalidated",
+                    "params": {
+                        "bundle_id": bundle.bundle_id,
+                        "owner_id": bundle.owner_id,
+                        "buyer_id": buyer_id,
+                        "refund": bundle.price,
+                        "ft_token_id": bundle.ft_token_id,
+                        "not_approved_tokens": not_approved
+                            .iter()
+                            .map(|item| json!({
+                                "nft_contract_id": item.nft_contract_id,
+                                "token_id": item.token_id,
+                            }))
+                            .collect::<Vec<_>>(),
+                    }
+                })
+                .to_string(),
+            );
+            return;
+        }
+
+        let transfers = bundle
+            .items
+            .iter()
+            .zip(&item_prices)
+            .map(|(item, item_price)| {
+                ext_contract::ext(item.nft_contract_id.clone())
+                    .with_attached_deposit(ONE_YOCTONEAR)
+                    .with_static_gas(GAS_FOR_NFT_TRANSFER)
+                    .nft_transfer_payout(
+                        buyer_id.clone(),
+                        item.token_id.clone(),
+                        item.approval_id.map(|x| x.0),
+                        None,
+                        *item_price,
+                        Some(self.max_len_payout),
+                    )
+            })
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .unwrap();
+        let resolve_gas = GAS_FOR_RESOLVE_BUNDLE_PURCHASE
+            .saturating_add(GAS_FOR_RESOLVE_BUNDLE_ITEM.saturating_mul(bundle.items.len() as u64));
+        transfers.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(resolve_gas)
+                .resolve_bundle_purchase(buyer_id, bundle, item_prices),
+        );
+    }
+
+    /// `ft_token_id` is the currency the payment arrived in, None for NEAR.
+    /// Returns the excess of `amount` over the bundle price, which is the buyer's to refund
+    pub(crate) fn internal_buy_bundle(
+        &mut self,
+        bundle_id: U64,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: u128,
+    ) -> u128 {
+        let bundle = self.bundles.get(&bundle_id.0).expect("DS: Bundle does not exist");
+        assert_ne!(bundle.owner_id, buyer_id, "DS: Cannot buy your own bundle");
+        assert_eq!(bundle.ft_token_id, ft_token_id, "DS: Bundle is priced in another currency");
+        assert!(bundle.is_approved(), "DS: Not every token of the bundle is approved yet");
+        assert!(
+            amount >= bundle.price.0,
+            "DS: Attached deposit is less than price {}",
+            bundle.price.0
+        );
+        self.internal_delete_bundle(&bundle);
+
+        let price = bundle.price.0;
+        let item_prices = bundle.item_prices();
+        let checks = bundle
+            .items
+            .iter()
+            .map(|item| {
+                ext_contract::ext(item.nft_contract_id.clone())
+                    .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+                    .nft_is_approved(item.token_id.clone(), env::current_account_id(), item.approval_id.map(|x| x.0))
+            })
+            .reduce(|checks, check| checks.and(check))
+            .unwrap();
+        let resolve_gas = GAS_FOR_RESOLVE_BUNDLE_APPROVALS.saturating_add(GAS_FOR_RESOLVE_BUNDLE_PURCHASE).saturating_add(
+            GAS_FOR_BUNDLE_TRANSFER_DISPATCH
+                .saturating_add(GAS_FOR_NFT_TRANSFER)
+                .saturating_add(GAS_FOR_RESOLVE_BUNDLE_ITEM)
+                .saturating_mul(bundle.items.len() as u64),
+        );
+        checks.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(resolve_gas)
+                .resolve_bundle_approvals(
+                    buyer_id,
+                    bundle,
+                    item_prices.into_iter().map(U128).collect(),
+                ),
+        );
+        amount - price
+    }
+
+    /// records the approval of one of the bundle's tokens, called from nft_on_approve
+    pub(crate) fn internal_approve_bundle_item(
+        &mut self,
+        bundle_id: U64,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+    ) {
+        let mut bundle = self.bundles.get(&bundle_id.0).expect("DS: Bundle does not exist");
+        assert_eq!(bundle.owner_id, owner_id, "DS: Bundle belongs to another account");
+        let item = bundle
+            .items
+            .iter_mut()
+            .find(|item| item.nft_contract_id == nft_contract_id && item.token_id == token_id)
+            .expect("DS: Token is not in the bundle");
+        item.approval_id = Some(approval_id.into());
+        self.bundles.insert(&bundle_id.0, &bundle);
+
+        env::log_str(
+            &json!({
+                "type": "approve_bundle_item",
+                "params": {
+                    "bundle_id": bundle_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "approval_id": U64(approval_id),
+                    "is_approved": bundle.is_approved(),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// while a bundle is open its tokens can't be listed, sold to an offer or swapped on their own
+    pub(crate) fn internal_assert_not_bundled(&self, nft_contract_id: &AccountId, token_id: &TokenId) {
+        let bundle_id = self
+            .bundled_tokens
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id));
+        assert!(
+            bundle_id.is_none(),
+            "DS: Token is in bundle {}, delete the bundle first",
+            bundle_id.unwrap_or_default()
+        );
+    }
+
+    fn internal_delete_bundle(&mut self, bundle: &Bundle) {
+        self.bundles.remove(&bundle.bundle_id.0);
+        for item in bundle.items.iter() {
+            self.bundled_tokens
+                .remove(&format!("{}{}{}", item.nft_contract_id, DELIMETER, item.token_id));
+        }
+        if let Some(mut bundle_ids) = self.bundles_by_owner_id.get(&bundle.owner_id) {
+            bundle_ids.remove(&bundle.bundle_id.0);
+            if bundle_ids.is_empty() {
+                self.bundles_by_owner_id.remove(&bundle.owner_id);
+            } else {
+                self.bundles_by_owner_id.insert(&bundle.owner_id, &bundle_ids);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000_000;
+
+    fn add_test_bundle(contract: &mut Marketplace) -> Bundle {
+        deposit_storage(contract, "seller.near");
+        set_context("seller.near", 1, 0);
+        let items = ["1", "2"]
+            .iter()
+            .map(|token_id| BundleItemArgs {
+                nft_contract_id: account(NFT),
+                token_id: token_id.to_string(),
+                weight: 1,
+            })
+            .collect();
+        let bundle_id = contract.add_bundle(items, U128(PRICE), None);
+        for token_id in ["1", "2"] {
+            let msg = json!({"market_type": "bundle", "bundle_id": bundle_id}).to_string();
+            approve(contract, "seller.near", token_id, 0, &msg);
+        }
+        contract.get_bundle(bundle_id).unwrap()
+    }
+
+    fn payout_to_seller(amount: u128) -> PromiseResult {
+        PromiseResult::Successful(format!(r#"{{"payout": {{"seller.near": "{}"}}}}"#, amount).into_bytes())
+    }
+
+    #[test]
+    fn a_partly_failed_bundle_charges_the_moved_tokens_only() {
+        let mut contract = new_marketplace();
+        let bundle = add_test_bundle(&mut contract);
+        let item_prices: Vec<U128> = bundle.item_prices().into_iter().map(U128).collect();
+
+        set_context_with_results("market.near", 0, 0, vec![payout_to_seller(PRICE / 2), PromiseResult::Failed]);
+        let refund = contract.resolve_bundle_purchase(account("buyer.near"), bundle, item_prices);
+
+        assert_eq!(refund, U128(PRICE / 2));
+        assert_eq!(pending(&contract, "buyer.near"), PRICE / 2);
+        assert_eq!(pending(&contract, "seller.near"), PRICE / 2 - PRICE / 2 * 250 / 10_000);
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("bundle_purchase_failed")));
+    }
+
+    #[test]
+    fn a_bundle_that_moved_pays_every_item() {
+        let mut contract = new_marketplace();
+        let bundle = add_test_bundle(&mut contract);
+        let item_prices: Vec<U128> = bundle.item_prices().into_iter().map(U128).collect();
+
+        set_context_with_results("market.near", 0, 0, vec![payout_to_seller(PRICE / 2), payout_to_seller(PRICE / 2)]);
+        contract.resolve_bundle_purchase(account("buyer.near"), bundle, item_prices);
+
+        assert_eq!(pending(&contract, "buyer.near"), 0);
+        assert_eq!(pending(&contract, "seller.near"), PRICE - PRICE * 250 / 10_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Token is in bundle 0")]
+    fn a_bundled_token_cannot_be_listed() {
+        let mut contract = new_marketplace();
+        add_test_bundle(&mut contract);
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+    }
+
+    #[test]
+    fn deleting_the_bundle_frees_its_tokens() {
+        let mut contract = new_marketplace();
+        let bundle = add_test_bundle(&mut contract);
+        set_context("seller.near", 1, 0);
+        contract.delete_bundle(bundle.bundle_id);
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        assert!(market_data_of(&contract, "1").is_some());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::*;
+
+// expired offers stay in the book until withdrawn or pruned, bound how many of them a match may skip
+const MAX_COLLECTION_OFFERS_SCAN: usize = 50;
+
+/// escrowed offer for any token of a collection, kept in a price sorted book per collection and currency
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOffer {
+    pub collection_offer_id: U64,
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub price: U128,          // per token
+    pub quantity: u32,        // tokens still wanted, escrow is price * quantity
+    pub ft_token_id: Option<AccountId>, // None means escrowed in NEAR
+    pub expires_at: U64,
+}
+
+/// book entries sort by price, then by age so that equal prices fill first come first served
+pub type CollectionOfferKey = (u128, u64);
+
+fn collection_offer_key(price: u128, collection_offer_id: u64) -> CollectionOfferKey {
+    (price, u64::MAX - collection_offer_id)
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[payable]
+    pub fn add_collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        price: U128,
+        quantity: u32,
+        expires_at: U64,
+    ) {
+        self.internal_add_collection_offer(
+            nft_contract_id,
+            env::predecessor_account_id(),
+            None,
+            price.0,
+            quantity,
+            expires_at,
+            env::attached_deposit().as_yoctonear(),
+        );
+    }
+
+    #[payable]
+    pub fn delete_collection_offer(&mut self, collection_offer_id: U64) {
+        assert_one_yocto();
+        let collection_offer = self
+            .collection_offers
+            .get(&collection_offer_id.0)
+            .expect("DS: Collection offer does not exist");
+        let buyer_id = env::predecessor_account_id();
+        assert_eq!(collection_offer.buyer_id, buyer_id, "DS: Buyer only");
+        assert!(
+            env::block_timestamp() > collection_offer.expires_at.0,
+            "DS: Offer has not expired yet"
+        );
+        self.internal_delete_collection_offer(&collection_offer);
+        self.internal_credit_pending(
+            collection_offer.ft_token_id,
+            buyer_id.clone(),
+            collection_offer.price.0 * collection_offer.quantity as u128,
+        );
+
+        env::log_str(
+            &json!({
+                "type": "delete_collection_offer",
+                "params": {
+                    "collection_offer_id": collection_offer_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": collection_offer.nft_contract_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// anyone can remove up to `limit` expired offers from the part of the book a match scans,
+    /// their escrow goes to the buyers' pending withdrawals. Returns how many were removed
+    pub fn prune_expired_collection_offers(
+        &mut self,
+        nft_contract_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        limit: Option<u64>,
+    ) -> U64 {
+        let book = match self
+            .collection_offer_book
+            .get(&collection_and_currency_id(&nft_contract_id, &ft_token_id))
+        {
+            Some(book) => book,
+            None => return U64(0),
+        };
+        let current_time = env::block_timestamp();
+        let expired: Vec<CollectionOffer> = book
+            .iter_rev()
+            .take(MAX_COLLECTION_OFFERS_SCAN)
+            .filter_map(|(_, collection_offer_id)| self.collection_offers.get(&collection_offer_id))
+            .filter(|collection_offer| collection_offer.expires_at.0 < current_time)
+            .take(limit.unwrap_or(10) as usize)
+            .collect();
+
+        for collection_offer in expired.iter() {
+            self.internal_delete_collection_offer(collection_offer);
+            self.internal_credit_pending(
+                collection_offer.ft_token_id.clone(),
+                collection_offer.buyer_id.clone(),
+                collection_offer.price.0 * collection_offer.quantity as u128,
+            );
+        }
+        env::log_str(
+            &json!({
+                "type": "prune_expired_collection_offers",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "ft_token_id": ft_token_id,
+                    "collection_offer_ids": expired
+                        .iter()
+                        .map(|collection_offer| collection_offer.collection_offer_id)
+                        .collect::<Vec<_>>(),
+                }
+            })
+            .to_string(),
+        );
+        U64(expired.len() as u64)
+    }
+
+    pub fn get_collection_offer(&self, collection_offer_id: U64) -> Option<CollectionOffer> {
+        self.collection_offers.get(&collection_offer_id.0)
+    }
+
+    /// best offers first
+    pub fn get_collection_offers(
+        &self,
+        nft_contract_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<CollectionOffer> {
+        let book = match self
+            .collection_offer_book
+            .get(&collection_and_currency_id(&nft_contract_id, &ft_token_id))
+        {
+            Some(book) => book,
+            None => return vec![],
+        };
+        book.iter_rev()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|(_, collection_offer_id)| self.collection_offers.get(&collection_offer_id))
+            .collect()
+    }
+
+    pub fn get_best_collection_offer(
+        &self,
+        nft_contract_id: AccountId,
+        ft_token_id: Option<AccountId>,
+    ) -> Option<CollectionOffer> {
+        self.internal_find_collection_offer(&nft_contract_id, &ft_token_id, None, 0)
+    }
+
+    pub fn get_collection_offers_by_bidder_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<CollectionOffer> {
+        let collection_offer_ids = match self.collection_offers_by_bidder_id.get(&account_id) {
+            Some(collection_offer_ids) => collection_offer_ids,
+            None => return vec![],
+        };
+        collection_offer_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|collection_offer_id| self.collection_offers.get(&collection_offer_id))
+            .collect()
+    }
+
+    pub fn get_supply_collection_offers_by_bidder_id(&self, account_id: AccountId) -> U64 {
+        self.collection_offers_by_bidder_id
+            .get(&account_id)
+            .map_or(0, |collection_offer_ids| collection_offer_ids.len())
+            .into()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_add_collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        price: u128,
+        quantity: u32,
+        expires_at: U64,
+        deposit: u128,
+    ) {
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "DS: nft_contract_id is not approved"
+        );
+        assert!(price > 0, "DS: Offer price must be greater than 0");
+        assert!(quantity > 0, "DS: Quantity must be greater than 0");
+        assert!(
+            expires_at.0 > env::block_timestamp(),
+            "DS: Offer expiry must be in the future"
+        );
+        assert_eq!(
+            deposit,
+            price * quantity as u128,
+            "DS: Deposit must equal price * quantity"
+        );
+
+        let storage_amount = self.storage_minimum_balance().0;
+        let buyer_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
+        let buyer_storage_required =
+            (self.internal_storage_count(&buyer_id) + 1) as u128 * storage_amount;
+        assert!(
+            buyer_paid_storage >= buyer_storage_required,
+            "DS: Insufficient storage paid: {}, for {} entries at {} rate of per entry",
+            buyer_paid_storage,
+            buyer_storage_required / storage_amount,
+            storage_amount
+        );
+
+        let collection_offer_id = self.next_collection_offer_id;
+        self.next_collection_offer_id += 1;
+        let collection_offer = CollectionOffer {
+            collection_offer_id: collection_offer_id.into(),
+            buyer_id: buyer_id.clone(),
+            nft_contract_id: nft_contract_id.clone(),
+            price: price.into(),
+            quantity,
+            ft_token_id: ft_token_id.clone(),
+            expires_at,
+        };
+        self.collection_offers.insert(&collection_offer_id, &collection_offer);
+
+        let collection_and_currency_id = collection_and_currency_id(&nft_contract_id, &ft_token_id);
+        let mut book = self
+            .collection_offer_book
+            .get(&collection_and_currency_id)
+            .unwrap_or_else(|| {
+                TreeMap::new(StorageKey::CollectionOfferBookInner {
+                    collection_and_currency_id_hash: env::sha256_array(collection_and_currency_id.as_bytes()),
+                })
+            });
+        book.insert(&collection_offer_key(price, collection_offer_id), &collection_offer_id);
+        self.collection_offer_book.insert(&collection_and_currency_id, &book);
+
+        let mut collection_offer_ids = self
+            .collection_offers_by_bidder_id
+            .get(&buyer_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::CollectionOffersByBidderIdInner {
+                    account_id_hash: hash_account_id(&buyer_id),
+                })
+            });
+        collection_offer_ids.insert(&collection_offer_id);
+        self.collection_offers_by_bidder_id.insert(&buyer_id, &collection_offer_ids);
+
+        env::log_str(
+            &json!({
+                "type": "add_collection_offer",
+                "params": {
+                    "collection_offer_id": U64(collection_offer_id),
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "price": U128(price),
+                    "quantity": quantity,
+                    "ft_token_id": ft_token_id,
+                    "expires_at": expires_at,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// best unexpired offer at or above `min_price` that `seller_id` is allowed to fill
+    pub(crate) fn internal_find_collection_offer(
+        &self,
+        nft_contract_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+        seller_id: Option<&AccountId>,
+        min_price: u128,
+    ) -> Option<CollectionOffer> {
+        let book = self
+            .collection_offer_book
+            .get(&collection_and_currency_id(nft_contract_id, ft_token_id))?;
+        let current_time = env::block_timestamp();
+        let collection_offer = book
+            .iter_rev()
+            .take(MAX_COLLECTION_OFFERS_SCAN)
+            .take_while(|((price, _), _)| *price >= min_price)
+            .filter_map(|(_, collection_offer_id)| self.collection_offers.get(&collection_offer_id))
+            .find(|collection_offer| {
+                collection_offer.expires_at.0 >= current_time
+                    && Some(&collection_offer.buyer_id) != seller_id
+            });
+        collection_offer
+    }
+
+    /// sells the approved token to the offer's buyer at the offer price, using up one unit of its quantity
+    pub(crate) fn internal_fill_collection_offer(
+        &mut self,
+        mut collection_offer: CollectionOffer,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+    ) -> Promise {
+        collection_offer.quantity -= 1;
+        if collection_offer.quantity == 0 {
+            self.internal_delete_collection_offer(&collection_offer);
+        } else {
+            self.collection_offers
+                .insert(&collection_offer.collection_offer_id.0, &collection_offer);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "fill_collection_offer",
+                "params": {
+                    "collection_offer_id": collection_offer.collection_offer_id,
+                    "owner_id": owner_id,
+                    "buyer_id": collection_offer.buyer_id,
+                    "nft_contract_id": collection_offer.nft_contract_id,
+                    "token_id": token_id,
+                    "price": collection_offer.price,
+                    "quantity": collection_offer.quantity,
+                }
+            })
+            .to_string(),
+        );
+
+        self.internal_sell_to_buyer(
+            collection_offer.nft_contract_id,
+            token_id,
+            owner_id,
+            approval_id,
+            collection_offer.buyer_id,
+            collection_offer.price.0,
+            collection_offer.ft_token_id,
+        )
+    }
+
+    fn internal_delete_collection_offer(&mut self, collection_offer: &CollectionOffer) {
+        let collection_offer_id = collection_offer.collection_offer_id.0;
+        self.collection_offers.remove(&collection_offer_id);
+
+        let collection_and_currency_id =
+            collection_and_currency_id(&collection_offer.nft_contract_id, &collection_offer.ft_token_id);
+        if let Some(mut book) = self.collection_offer_book.get(&collection_and_currency_id) {
+            book.remove(&collection_offer_key(collection_offer.price.0, collection_offer_id));
+            if book.is_empty() {
+                self.collection_offer_book.remove(&collection_and_currency_id);
+            } else {
+                self.collection_offer_book.insert(&collection_and_currency_id, &book);
+            }
+        }
+        if let Some(mut collection_offer_ids) =
+            self.collection_offers_by_bidder_id.get(&collection_offer.buyer_id)
+        {
+            collection_offer_ids.remove(&collection_offer_id);
+            if collection_offer_ids.is_empty() {
+                self.collection_offers_by_bidder_id.remove(&collection_offer.buyer_id);
+            } else {
+                self.collection_offers_by_bidder_id
+                    .insert(&collection_offer.buyer_id, &collection_offer_ids);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn add_test_offer(contract: &mut Marketplace, buyer_id: &str, price: u128, expires_at: u64) {
+        deposit_storage(contract, buyer_id);
+        set_context(buyer_id, price, 0);
+        contract.add_collection_offer(account(NFT), U128(price), 1, U64(expires_at));
+    }
+
+    fn best_offer_price(contract: &Marketplace) -> Option<u128> {
+        contract
+            .get_best_collection_offer(account(NFT), None)
+            .map(|collection_offer| collection_offer.price.0)
+    }
+
+    #[test]
+    fn a_sale_at_or_below_the_best_offer_fills_it() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract, "low.near", 100, 1_000);
+        add_test_offer(&mut contract, "high.near", 200, 1_000);
+        list(&mut contract, "seller.near", "1", fixed_price(150));
+
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+        assert_eq!(market_data.price, 200);
+        assert_eq!(market_data.lock.unwrap().buyer_id, account("high.near"));
+        assert_eq!(best_offer_price(&contract), Some(100));
+    }
+
+    #[test]
+    fn a_sale_above_every_offer_is_listed() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract, "buyer.near", 100, 1_000);
+        list(&mut contract, "seller.near", "1", fixed_price(150));
+
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Active);
+        assert_eq!(best_offer_price(&contract), Some(100));
+    }
+
+    #[test]
+    fn an_expired_offer_is_not_filled() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract, "buyer.near", 200, 10);
+        set_context("seller.near", 0, 20);
+        assert_eq!(best_offer_price(&contract), None);
+    }
+
+    #[test]
+    fn pruning_expired_offers_unblocks_the_book_and_refunds_them() {
+        let mut contract = new_marketplace();
+        for _ in 0..MAX_COLLECTION_OFFERS_SCAN {
+            add_test_offer(&mut contract, "stale.near", 200, 10);
+        }
+        add_test_offer(&mut contract, "buyer.near", 100, 1_000);
+
+        set_context("keeper.near", 0, 20);
+        assert_eq!(best_offer_price(&contract), None);
+        let limit = MAX_COLLECTION_OFFERS_SCAN as u64;
+        assert_eq!(contract.prune_expired_collection_offers(account(NFT), None, Some(limit)), U64(limit));
+        assert_eq!(pending(&contract, "stale.near"), 200 * MAX_COLLECTION_OFFERS_SCAN as u128);
+        assert_eq!(best_offer_price(&contract), Some(100));
+    }
+}
@@ -9,9 +9,12 @@ trait ExtContract {
         receiver_id: AccountId,
         token_id: TokenId,
         approval_id: Option<u64>,
-        balance: Option<U128>,
+        memo: Option<String>,
+        balance: U128,
+        max_len_payout: Option<u32>,
     );
     fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>);
+    fn nft_is_approved(&self, token_id: TokenId, approved_account_id: AccountId, approval_id: Option<u64>) -> bool;
 }
 
 /// TODO: this should be in the near_standard_contracts
@@ -19,3 +22,10 @@ trait ExtContract {
 pub trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
+
+/// views a holder gate is checked with
+#[ext_contract(ext_holder_check)]
+trait HolderCheck {
+    fn nft_supply_for_owner(&self, account_id: AccountId) -> U128;
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
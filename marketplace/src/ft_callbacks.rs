@@ -0,0 +1,155 @@
+use crate::*;
+/// transfer callbacks from FT Contracts
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+pub enum FtOnTransferArgs {
+    Buy {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    },
+    AddBid {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    },
+    AddOffer {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        expires_at: U64,
+    },
+    AddCollectionOffer {
+        nft_contract_id: AccountId,
+        price: U128,
+        quantity: u32,
+        expires_at: U64,
+    },
+    CommitSealedBid {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        commitment: Base58CryptoHash,
+    },
+    DepositBiddingBalance,
+    BuyBundle {
+        bundle_id: U64,
+    },
+    Sweep {
+        target: SweepTarget,
+        max_total: Option<U128>,
+    },
+}
+
+trait FungibleTokenReceiver {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Marketplace {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let ft_token_id = env::predecessor_account_id();
+        assert!(
+            self.approved_ft_token_ids.contains(&ft_token_id),
+            "DS: ft_token_id is not approved"
+        );
+
+        match near_sdk::serde_json::from_str(&msg).expect("Not valid FtOnTransferArgs") {
+            FtOnTransferArgs::Buy {
+                nft_contract_id,
+                token_id,
+            } => {
+                if let Some(holder_gate) = self.internal_holder_gate(&nft_contract_id, &token_id) {
+                    return PromiseOrValue::Promise(self.internal_check_holder(
+                        holder_gate,
+                        GatedAction::Buy {
+                            nft_contract_id,
+                            token_id,
+                            buyer_id: sender_id,
+                            ft_token_id: Some(ft_token_id),
+                            amount,
+                        },
+                    ));
+                }
+                // anything above the current price goes back to the buyer through ft_resolve_transfer
+                let excess = self.internal_buy(nft_contract_id, token_id, sender_id, Some(ft_token_id), amount.0);
+                return PromiseOrValue::Value(U128(excess));
+            }
+            FtOnTransferArgs::AddBid {
+                nft_contract_id,
+                token_id,
+            } => {
+                if let Some(holder_gate) = self.internal_holder_gate(&nft_contract_id, &token_id) {
+                    return PromiseOrValue::Promise(self.internal_check_holder(
+                        holder_gate,
+                        GatedAction::AddBid {
+                            nft_contract_id,
+                            token_id,
+                            bidder_id: sender_id,
+                            ft_token_id: Some(ft_token_id),
+                            amount,
+                            from_bidding_balance: false,
+                        },
+                    ));
+                }
+                self.internal_add_bid(nft_contract_id, token_id, sender_id, Some(ft_token_id), amount, false);
+            }
+            FtOnTransferArgs::AddOffer {
+                nft_contract_id,
+                token_id,
+                expires_at,
+            } => {
+                self.internal_add_offer(nft_contract_id, token_id, sender_id, Some(ft_token_id), amount.0, expires_at);
+            }
+            FtOnTransferArgs::AddCollectionOffer {
+                nft_contract_id,
+                price,
+                quantity,
+                expires_at,
+            } => {
+                self.internal_add_collection_offer(
+                    nft_contract_id,
+                    sender_id,
+                    Some(ft_token_id),
+                    price.0,
+                    quantity,
+                    expires_at,
+                    amount.0,
+                );
+            }
+            FtOnTransferArgs::CommitSealedBid {
+                nft_contract_id,
+                token_id,
+                commitment,
+            } => {
+                self.internal_commit_sealed_bid(nft_contract_id, token_id, sender_id, Some(ft_token_id), amount.0, commitment);
+            }
+            FtOnTransferArgs::DepositBiddingBalance => {
+                self.internal_deposit_bidding_balance(sender_id, Some(ft_token_id), amount.0);
+            }
+            FtOnTransferArgs::BuyBundle { bundle_id } => {
+                let excess = self.internal_buy_bundle(bundle_id, sender_id, Some(ft_token_id), amount.0);
+                return PromiseOrValue::Value(U128(excess));
+            }
+            FtOnTransferArgs::Sweep { target, max_total } => {
+                // what the sweep does not spend is refunded through resolve_sweep
+                return PromiseOrValue::Promise(self.internal_sweep(
+                    target,
+                    sender_id,
+                    Some(ft_token_id),
+                    amount.0,
+                    max_total,
+                ));
+            }
+        }
+
+        // the whole amount is now held by the marketplace, failed purchases are refunded in resolve_purchase
+        PromiseOrValue::Value(U128(0))
+    }
+}
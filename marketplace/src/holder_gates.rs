@@ -0,0 +1,287 @@
+use crate::*;
+
+const GAS_FOR_HOLDER_CHECK: Gas = Gas::from_tgas(10);
+// a buy reserves 145 Tgas for its approval check, transfer and resolve_purchase on top of its own work
+const GAS_FOR_COMMIT_GATED_ACTION: Gas = Gas::from_tgas(165);
+const GAS_FOR_RESOLVE_GATED_ACTION: Gas = Gas::from_tgas(10);
+// the resolver commits the action and resolves it when the check passes
+const GAS_FOR_RESOLVE_HOLDER_CHECK: Gas = Gas::from_tgas(190);
+
+/// who may buy or bid on a gated sale, checked with a view call on the gating contract
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum HolderGate {
+    NftHolder {
+        nft_contract_id: AccountId,
+        min_tokens: U128, // compared to nft_supply_for_owner
+    },
+    FtHolder {
+        ft_token_id: AccountId,
+        min_balance: U128, // compared to ft_balance_of
+    },
+}
+
+impl HolderGate {
+    pub fn min_held(&self) -> u128 {
+        match self {
+            HolderGate::NftHolder { min_tokens, .. } => min_tokens.0,
+            HolderGate::FtHolder { min_balance, .. } => min_balance.0,
+        }
+    }
+
+    pub fn assert_valid(&self) {
+        assert!(self.min_held() > 0, "DS: holder_gate must require more than 0");
+    }
+}
+
+/// a buy or bid waiting for its holder check, amount is what the marketplace holds for it
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+pub enum GatedAction {
+    Buy {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: U128,
+    },
+    AddBid {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        bidder_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: U128,
+        from_bidding_balance: bool,
+    },
+}
+
+impl GatedAction {
+    fn listing(&self) -> (&AccountId, &TokenId) {
+        match self {
+            GatedAction::Buy { nft_contract_id, token_id, .. }
+            | GatedAction::AddBid { nft_contract_id, token_id, .. } => (nft_contract_id, token_id),
+        }
+    }
+
+    fn account_id(&self) -> &AccountId {
+        match self {
+            GatedAction::Buy { buyer_id, .. } => buyer_id,
+            GatedAction::AddBid { bidder_id, .. } => bidder_id,
+        }
+    }
+
+    /// what goes back to the account when the action does not go through
+    fn escrowed(&self) -> u128 {
+        match self {
+            GatedAction::Buy { amount, .. } => amount.0,
+            GatedAction::AddBid { from_bidding_balance: true, .. } => 0,
+            GatedAction::AddBid { amount, .. } => amount.0,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[private]
+    pub fn resolve_holder_check(&mut self, holder_gate: HolderGate, action: GatedAction) -> PromiseOrValue<U128> {
+        let held = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<U128>(&value).ok())
+            .map_or(0, |x| x.0);
+        let (nft_contract_id, token_id) = action.listing();
+        // the sale may have been replaced while the check was in flight
+        let is_same_gate = self.internal_holder_gate(nft_contract_id, token_id).as_ref() == Some(&holder_gate);
+        if !is_same_gate || held < holder_gate.min_held() {
+            env::log_str(
+                &json!({
+                    "type": "holder_check_failed",
+                    "params": {
+                        "account_id": action.account_id(),
+                        "nft_contract_id": nft_contract_id,
+                        "token_id": token_id,
+                        "holder_gate": holder_gate,
+                        "held": U128(held),
+                    }
+                })
+                .to_string(),
+            );
+            return PromiseOrValue::Value(U128(self.internal_refund_gated_action(&action, action.escrowed())));
+        }
+
+        // committed in its own receipt so that a failing buy or bid is refunded instead of keeping the funds
+        PromiseOrValue::Promise(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_COMMIT_GATED_ACTION)
+                .commit_gated_action(action.clone())
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_RESOLVE_GATED_ACTION)
+                        .resolve_gated_action(action),
+                ),
+        )
+    }
+
+    /// returns the excess of a buy, a bid uses its whole amount
+    #[private]
+    pub fn commit_gated_action(&mut self, action: GatedAction) -> U128 {
+        match action {
+            GatedAction::Buy {
+                nft_contract_id,
+                token_id,
+                buyer_id,
+                ft_token_id,
+                amount,
+            } => U128(self.internal_buy(nft_contract_id, token_id, buyer_id, ft_token_id, amount.0)),
+            GatedAction::AddBid {
+                nft_contract_id,
+                token_id,
+                bidder_id,
+                ft_token_id,
+                amount,
+                from_bidding_balance,
+            } => {
+                self.internal_add_bid(nft_contract_id, token_id, bidder_id, ft_token_id, amount, from_bidding_balance);
+                U128(0)
+            }
+        }
+    }
+
+    /// returns what the fungible token contract has to refund through ft_resolve_transfer
+    #[private]
+    pub fn resolve_gated_action(&mut self, action: GatedAction) -> U128 {
+        let unused = match promise_result_as_success() {
+            Some(value) => near_sdk::serde_json::from_slice::<U128>(&value).map_or(0, |x| x.0),
+            None => action.escrowed(),
+        };
+        U128(self.internal_refund_gated_action(&action, unused))
+    }
+
+    pub(crate) fn internal_holder_gate(&self, nft_contract_id: &AccountId, token_id: &TokenId) -> Option<HolderGate> {
+        self.market
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .and_then(|market_data| market_data.holder_gate)
+    }
+
+    /// first step of a buy or bid on a gated sale, the funds stay with the marketplace until the check resolves
+    pub(crate) fn internal_check_holder(&self, holder_gate: HolderGate, action: GatedAction) -> Promise {
+        let account_id = action.account_id().clone();
+        let check = match &holder_gate {
+            HolderGate::NftHolder { nft_contract_id, .. } => ext_holder_check::ext(nft_contract_id.clone())
+                .with_static_gas(GAS_FOR_HOLDER_CHECK)
+                .nft_supply_for_owner(account_id),
+            HolderGate::FtHolder { ft_token_id, .. } => ext_holder_check::ext(ft_token_id.clone())
+                .with_static_gas(GAS_FOR_HOLDER_CHECK)
+                .ft_balance_of(account_id),
+        };
+        check.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_HOLDER_CHECK)
+                .resolve_holder_check(holder_gate, action),
+        )
+    }
+
+    /// NEAR goes to pending withdrawals, fungible tokens are returned to be refunded by their contract
+    fn internal_refund_gated_action(&mut self, action: &GatedAction, amount: u128) -> u128 {
+        let (account_id, ft_token_id) = match action {
+            GatedAction::Buy { buyer_id, ft_token_id, .. } => (buyer_id, ft_token_id),
+            GatedAction::AddBid { bidder_id, ft_token_id, .. } => (bidder_id, ft_token_id),
+        };
+        if ft_token_id.is_some() {
+            return amount;
+        }
+        self.internal_credit_pending(None, account_id.clone(), amount);
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000;
+
+    fn holder_gate() -> HolderGate {
+        HolderGate::NftHolder {
+            nft_contract_id: account("pass.near"),
+            min_tokens: U128(1),
+        }
+    }
+
+    fn list_gated(contract: &mut Marketplace) {
+        list(
+            contract,
+            "seller.near",
+            "1",
+            MarketArgs {
+                price: Some(U128(PRICE)),
+                holder_gate: Some(holder_gate()),
+                ..Default::default()
+            },
+        );
+    }
+
+    fn buy_action(amount: u128) -> GatedAction {
+        GatedAction::Buy {
+            nft_contract_id: account(NFT),
+            token_id: "1".to_string(),
+            buyer_id: account("buyer.near"),
+            ft_token_id: None,
+            amount: U128(amount),
+        }
+    }
+
+    fn held(tokens: u128) -> Vec<PromiseResult> {
+        vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(tokens)).unwrap())]
+    }
+
+    #[test]
+    fn a_buyer_below_the_gate_is_refunded() {
+        let mut contract = new_marketplace();
+        list_gated(&mut contract);
+        set_context_with_results("market.near", 0, 0, held(0));
+        contract.resolve_holder_check(holder_gate(), buy_action(PRICE));
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+        assert_eq!(market_data_of(&contract, "1").unwrap().status, ListingStatus::Active);
+    }
+
+    #[test]
+    fn a_check_against_a_replaced_gate_is_refunded() {
+        let mut contract = new_marketplace();
+        list_gated(&mut contract);
+        let other_gate = HolderGate::NftHolder {
+            nft_contract_id: account("pass.near"),
+            min_tokens: U128(2),
+        };
+        set_context_with_results("market.near", 0, 0, held(5));
+        contract.resolve_holder_check(other_gate, buy_action(PRICE));
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+    }
+
+    #[test]
+    fn a_holder_commits_the_buy_and_gets_the_excess_back() {
+        let mut contract = new_marketplace();
+        list_gated(&mut contract);
+        set_context("market.near", 0, 0);
+        assert_eq!(contract.commit_gated_action(buy_action(PRICE + 10)), U128(10));
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(10)).unwrap())],
+        );
+        contract.resolve_gated_action(buy_action(PRICE + 10));
+        assert_eq!(pending(&contract, "buyer.near"), 10);
+    }
+
+    #[test]
+    fn a_commit_that_failed_is_refunded_in_full() {
+        let mut contract = new_marketplace();
+        list_gated(&mut contract);
+        set_context_with_results("market.near", 0, 0, vec![PromiseResult::Failed]);
+        contract.resolve_gated_action(buy_action(PRICE));
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+    }
+}
@@ -1,15 +1,55 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
-use near_sdk::json_types::{U128, U64};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
     assert_one_yocto, env, ext_contract, near_bindgen, serde_json::json, AccountId,
-    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, is_promise_success, promise_result_as_success, NearToken };
+    BorshStorageKey, CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue, is_promise_success, promise_result_as_success, NearToken, PromiseResult };
 use std::collections::HashMap;
+use crate::auction_rules::*;
+use crate::bidding_balances::*;
+use crate::bids::*;
+use crate::bundles::*;
+use crate::collection_offers::*;
 use crate::external::*;
+use crate::holder_gates::*;
+use crate::listing_status::*;
+use crate::nft_callbacks::*;
+use crate::offers::*;
+use crate::payouts::*;
+use crate::price_curve::*;
+use crate::price_index::*;
+use crate::reservations::*;
+use crate::sealed_auctions::*;
+use crate::stale_listings::*;
+use crate::swaps::*;
+use crate::sweeps::*;
 
+mod auction_rules;
+mod bidding_balances;
+mod bids;
+mod bundles;
+mod collection_offers;
 mod external;
+mod ft_callbacks;
+mod holder_gates;
+mod listing_management;
+mod listing_status;
+mod migration;
 mod nft_callbacks;
+mod offers;
+mod payouts;
+mod pending_withdrawals;
+mod price_curve;
+mod price_index;
+mod reservations;
+mod sealed_auctions;
+mod settlement;
+mod stale_listings;
+mod swaps;
+mod sweeps;
+#[cfg(test)]
+mod test_utils;
 
 pub const FIVE_MINUTES: u64 = 300000000000;
 const DELIMETER: &str = "||";
@@ -18,9 +58,11 @@ pub const STORAGE_ADD_MARKET_DATA: u128 = 8590000000000000000000;
 const ONE_YOCTONEAR: NearToken = NearToken::from_yoctonear(1);
 const GAS_FOR_RESOLVE_PURCHASE: Gas = Gas::from_tgas(115);
 const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(15);
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
 
 pub type TokenId = String;
 pub type ContractAndTokenId = String;
+pub type ContractTokenAndBuyerId = String;
 pub type PayoutHashMap = HashMap<AccountId, U128>;
 
 #[derive(Serialize, Deserialize)]
@@ -35,6 +77,10 @@ pub struct MarketplaceConfig {
     pub owner_id: AccountId,
     pub treasury_id: AccountId,
     pub transaction_fee: u16,
+    pub bidding_commitment_bps: u32,
+    pub keeper_reward_bps: u16,
+    pub max_len_payout: u32,
+    pub max_royalty_bps: u16,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,23 +91,23 @@ pub struct MarketDataJson {
     nft_contract_id: AccountId,
     token_id: TokenId,
     price: U128,
-    bids: Option<Bids>,
+    top_bid: Option<Bid>,
+    bid_count: U64, // the bids themselves are paged through get_bids
     started_at: Option<U64>,
     ended_at: Option<U64>,
     end_price: Option<U128>, // dutch auction
     is_auction: Option<bool>,
+    ft_token_id: Option<AccountId>,
+    price_curve: Option<PriceCurve>,
+    auction_rules: Option<AuctionRules>,
+    reserve_met: Option<bool>, // the reserve price itself stays hidden
+    buy_now_price: Option<U128>,
+    sealed_bid_rules: Option<SealedBidRules>,
+    status: ListingStatus,
+    reservation: Option<Reservation>,
+    holder_gate: Option<HolderGate>,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct Bid {
-    pub bidder_id: AccountId,
-    pub price: U128,
-    pub time: u64
-}
-
-pub type Bids = Vec<Bid>;
-
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MarketData {
@@ -70,11 +116,34 @@ pub struct MarketData {
     pub nft_contract_id: AccountId,
     pub token_id: TokenId,
     pub price: u128,            // if auction, price becomes starting price
-    pub bids: Option<Bids>,
     pub started_at: Option<u64>,
     pub ended_at: Option<u64>,
     pub end_price: Option<u128>, // dutch auction
     pub is_auction: Option<bool>,
+    pub ft_token_id: Option<AccountId>, // None means priced in NEAR
+    pub price_curve: Option<PriceCurve>, // dutch auction, None means linear
+    pub auction_rules: Option<AuctionRules>, // set on english auctions only
+    pub reserve_price: Option<u128>, // english auction, not shown in views
+    pub buy_now_price: Option<u128>, // english auction
+    pub sealed_bid_rules: Option<SealedBidRules>, // sealed auction, bids are kept in sealed_bids
+    pub status: ListingStatus,
+    pub reservation: Option<Reservation>, // private sale
+    pub holder_gate: Option<HolderGate>, // buyers and bidders are checked with the gating contract first
+    pub lock: Option<ListingLock>, // set while Locked
+}
+
+impl MarketData {
+    /// neither an english nor a dutch auction, only these are kept in the price index
+    pub fn is_fixed_price(&self) -> bool {
+        !self.is_auction.unwrap_or(false) && self.end_price.is_none()
+    }
+
+    /// an auction without a reserve price always meets it
+    pub fn is_reserve_met(&self, top_bid: Option<&Bid>) -> bool {
+        self.reserve_price.is_none_or(|reserve_price| {
+            top_bid.is_some_and(|bid| bid.price.0 >= reserve_price)
+        })
+    }
 }
 
 #[near_bindgen]
@@ -83,10 +152,42 @@ pub struct Marketplace {
     pub owner_id: AccountId,
     pub treasury_id: AccountId,
     pub approved_nft_contract_ids: UnorderedSet<AccountId>,
+    pub approved_ft_token_ids: UnorderedSet<AccountId>,
     pub storage_deposits: LookupMap<AccountId, u128>,
     pub transaction_fee: u16,
     pub by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub by_nft_contract_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub by_price: LookupMap<String, TreeMap<PriceAndTokenId, ()>>,
     pub market: UnorderedMap<ContractAndTokenId, MarketData>,
+    pub offers: UnorderedMap<ContractTokenAndBuyerId, Offer>,
+    pub offers_by_token: LookupMap<ContractAndTokenId, UnorderedSet<AccountId>>,
+    pub offers_by_bidder_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    pub collection_offers: LookupMap<u64, CollectionOffer>,
+    pub collection_offer_book: LookupMap<String, TreeMap<CollectionOfferKey, u64>>,
+    pub collection_offers_by_bidder_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_collection_offer_id: u64,
+    pub sealed_bids: LookupMap<ContractTokenAndBuyerId, SealedBid>,
+    pub sealed_bidders: LookupMap<ContractAndTokenId, UnorderedSet<AccountId>>,
+    pub pending_withdrawals: LookupMap<AccountId, UnorderedMap<Option<AccountId>, u128>>,
+    pub bids: LookupMap<ContractTokenAndBuyerId, Bid>,
+    pub bid_book: LookupMap<ContractAndTokenId, TreeMap<PriceAndBidderId, ()>>,
+    pub bids_by_bidder_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    pub bidding_balances: LookupMap<AccountId, UnorderedMap<Option<AccountId>, BiddingBalance>>,
+    pub bidding_commitment_bps: u32, // how far open bids may exceed a bidding balance, 10000 means not at all
+    pub keeper_reward_bps: u16, // share of the marketplace fee paid to whoever settles an ended listing
+    pub by_ended_at: TreeMap<(u64, ContractAndTokenId), ()>,
+    pub bundles: LookupMap<u64, Bundle>,
+    pub bundles_by_owner_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_bundle_id: u64,
+    pub swaps: LookupMap<u64, Swap>,
+    pub swaps_by_proposer_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub swaps_by_counterparty_id: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_swap_id: u64,
+    pub escrowed_tokens: LookupMap<ContractAndTokenId, AccountId>, // swapped tokens whose release failed, by receiver
+    pub max_len_payout: u32, // most receivers a payout may have
+    pub max_royalty_bps: u16, // share of a sale that may go to receivers other than the seller
+    pub bundled_tokens: LookupMap<ContractAndTokenId, u64>, // tokens of open bundles, by bundle_id
+    pub sealed_bids_by_bidder_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -96,6 +197,47 @@ pub enum StorageKey {
     ByOwnerId,
     Market,
     ByOwnerIdInner { account_id_hash: CryptoHash },
+    FTTokenIds,
+    Listings,
+    Offers,
+    OffersByToken,
+    OffersByTokenInner { contract_and_token_id_hash: CryptoHash },
+    OffersByBidderId,
+    OffersByBidderIdInner { account_id_hash: CryptoHash },
+    CollectionOffers,
+    CollectionOfferBook,
+    CollectionOfferBookInner { collection_and_currency_id_hash: CryptoHash },
+    CollectionOffersByBidderId,
+    CollectionOffersByBidderIdInner { account_id_hash: CryptoHash },
+    ByNFTContractId,
+    ByNFTContractIdInner { account_id_hash: CryptoHash },
+    ByPrice,
+    ByPriceInner { collection_and_currency_id_hash: CryptoHash },
+    SealedBids,
+    SealedBidders,
+    SealedBiddersInner { contract_and_token_id_hash: CryptoHash },
+    PendingWithdrawals,
+    PendingWithdrawalsInner { account_id_hash: CryptoHash },
+    Bids,
+    BidBook,
+    BidBookInner { contract_and_token_id_hash: CryptoHash },
+    BidsByBidderId,
+    BidsByBidderIdInner { account_id_hash: CryptoHash },
+    BiddingBalances,
+    BiddingBalancesInner { account_id_hash: CryptoHash },
+    ByEndedAt,
+    Bundles,
+    BundlesByOwnerId,
+    BundlesByOwnerIdInner { account_id_hash: CryptoHash },
+    Swaps,
+    SwapsByProposerId,
+    SwapsByProposerIdInner { account_id_hash: CryptoHash },
+    SwapsByCounterpartyId,
+    SwapsByCounterpartyIdInner { account_id_hash: CryptoHash },
+    EscrowedTokens,
+    BundledTokens,
+    SealedBidsByBidderId,
+    SealedBidsByBidderIdInner { account_id_hash: CryptoHash },
 }
 
 #[near_bindgen]
@@ -105,6 +247,7 @@ impl Marketplace {
         owner_id: AccountId,
         treasury_id: AccountId,
         approved_nft_contract_ids: Option<Vec<AccountId>>,
+        approved_ft_token_ids: Option<Vec<AccountId>>,
         current_fee: u16
     ) -> Self {
         let mut this = Self {
@@ -113,58 +256,242 @@ impl Marketplace {
             treasury_id: treasury_id.into(),
             storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
             by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
+            by_price: LookupMap::new(StorageKey::ByPrice),
             approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
-            market: UnorderedMap::new(StorageKey::Market),
+            approved_ft_token_ids: UnorderedSet::new(StorageKey::FTTokenIds),
+            // Market still holds the listings of the first release after migrate
+            market: UnorderedMap::new(StorageKey::Listings),
+            offers: UnorderedMap::new(StorageKey::Offers),
+            offers_by_token: LookupMap::new(StorageKey::OffersByToken),
+            offers_by_bidder_id: LookupMap::new(StorageKey::OffersByBidderId),
+            collection_offers: LookupMap::new(StorageKey::CollectionOffers),
+            collection_offer_book: LookupMap::new(StorageKey::CollectionOfferBook),
+            collection_offers_by_bidder_id: LookupMap::new(StorageKey::CollectionOffersByBidderId),
+            next_collection_offer_id: 0,
+            sealed_bids: LookupMap::new(StorageKey::SealedBids),
+            sealed_bidders: LookupMap::new(StorageKey::SealedBidders),
+            pending_withdrawals: LookupMap::new(StorageKey::PendingWithdrawals),
+            bids: LookupMap::new(StorageKey::Bids),
+            bid_book: LookupMap::new(StorageKey::BidBook),
+            bids_by_bidder_id: LookupMap::new(StorageKey::BidsByBidderId),
+            bidding_balances: LookupMap::new(StorageKey::BiddingBalances),
+            bidding_commitment_bps: 10_000,
+            keeper_reward_bps: 0,
+            by_ended_at: TreeMap::new(StorageKey::ByEndedAt),
+            bundles: LookupMap::new(StorageKey::Bundles),
+            bundles_by_owner_id: LookupMap::new(StorageKey::BundlesByOwnerId),
+            next_bundle_id: 0,
+            swaps: LookupMap::new(StorageKey::Swaps),
+            swaps_by_proposer_id: LookupMap::new(StorageKey::SwapsByProposerId),
+            swaps_by_counterparty_id: LookupMap::new(StorageKey::SwapsByCounterpartyId),
+            next_swap_id: 0,
+            escrowed_tokens: LookupMap::new(StorageKey::EscrowedTokens),
+            max_len_payout: 10,
+            max_royalty_bps: 10_000,
+            bundled_tokens: LookupMap::new(StorageKey::BundledTokens),
+            sealed_bids_by_bidder_id: LookupMap::new(StorageKey::SealedBidsByBidderId),
         };
         add_accounts(
             approved_nft_contract_ids,
             &mut this.approved_nft_contract_ids,
         );
+        add_accounts(
+            approved_ft_token_ids,
+            &mut this.approved_ft_token_ids,
+        );
         this
     }
     
     #[payable]
     pub fn buy(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
-        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data doesn't exist");
         let buyer_id = env::predecessor_account_id();
+        if let Some(holder_gate) = self.internal_holder_gate(&nft_contract_id, &token_id) {
+            self.internal_check_holder(
+                holder_gate,
+                GatedAction::Buy {
+                    nft_contract_id,
+                    token_id,
+                    buyer_id,
+                    ft_token_id: None,
+                    amount: env::attached_deposit().as_yoctonear().into(),
+                },
+            );
+            return;
+        }
+        let excess = self.internal_buy(
+            nft_contract_id,
+            token_id,
+            buyer_id.clone(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+        );
+        if excess > 0 {
+            self.internal_credit_pending(None, buyer_id, excess);
+        }
+    }
+
+    /// without an attached deposit the bid draws on the bidder's bidding balance in `ft_token_id`
+    #[payable]
+    pub fn add_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        amount: U128,
+        ft_token_id: Option<AccountId>,
+    ) {
+        let bidder_id = env::predecessor_account_id();
+        let from_bidding_balance = env::attached_deposit().is_zero();
+        if !from_bidding_balance {
+            assert!(
+                ft_token_id.is_none(),
+                "DS: Bids in fungible tokens are paid through ft_transfer_call"
+            );
+            assert!(
+                env::attached_deposit() >= NearToken::from_yoctonear(amount.into()),
+                "DS: attached deposit is less than amount"
+            );
+        }
+        if let Some(holder_gate) = self.internal_holder_gate(&nft_contract_id, &token_id) {
+            self.internal_check_holder(
+                holder_gate,
+                GatedAction::AddBid {
+                    nft_contract_id,
+                    token_id,
+                    bidder_id,
+                    ft_token_id,
+                    amount,
+                    from_bidding_balance,
+                },
+            );
+            return;
+        }
+        self.internal_add_bid(nft_contract_id, token_id, bidder_id, ft_token_id, amount, from_bidding_balance);
+    }
+
+    /// `ft_token_id` is the currency the payment arrived in, None for NEAR.
+    /// The buyer pays the current price, the returned excess of `amount` is theirs to refund
+    fn internal_buy(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: u128,
+    ) -> u128 {
+        let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self.market.get(&contract_and_token_id).expect("DS: Market data doesn't exist");
+        assert_eq!(
+            self.internal_refresh_status(&mut market_data),
+            ListingStatus::Active,
+            "DS: Sale is not active"
+        );
         assert_ne!(
             buyer_id, market_data.owner_id,
             "DS: Cannot buy your own sale"
         );
-        assert_eq!(env::attached_deposit().as_yoctonear(), market_data.price, "DS: Insufficient Balance");
-        self.internal_process_purchase(nft_contract_id.into(), token_id, buyer_id, env::attached_deposit().as_yoctonear());  
+        market_data.assert_reserved_for(&buyer_id);
+        assert_eq!(market_data.ft_token_id, ft_token_id, "DS: Payment token does not match the sale");
+        assert!(
+            market_data.sealed_bid_rules.is_none(),
+            "DS: Sealed auction can't be bought outright"
+        );
+        // english auctions can only be bought outright at their buy now price, which ends them
+        let price = if market_data.auction_rules.is_some() {
+            market_data.buy_now_price.expect("DS: Auction has no buy now price")
+        } else {
+            self.internal_current_price(&market_data)
+        };
+        assert!(
+            amount >= price,
+            "DS: Insufficient Balance, current price is {}",
+            price
+        );
+        self.internal_process_purchase(nft_contract_id, token_id, buyer_id, price, None);
+        amount - price
     }
-    #[payable]
-    pub fn add_bid(
+
+    fn internal_add_bid(
         &mut self,
         nft_contract_id: AccountId,
         token_id: TokenId,
-        amount: U128
+        bidder_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: U128,
+        from_bidding_balance: bool,
     ) {
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
         let mut market_data = self
             .market
             .get(&contract_and_token_id)
             .expect("DS: Token id does not exist");
-        let bidder_id = env::predecessor_account_id();
+        assert_eq!(market_data.ft_token_id, ft_token_id, "DS: Payment token does not match the sale");
         let current_time = env::block_timestamp();
-        if market_data.started_at.is_some() {
+        let auction_rules = market_data
+            .auction_rules
+            .clone()
+            .expect("DS: Sale is not an english auction");
+        match self.internal_refresh_status(&mut market_data) {
+            ListingStatus::Active => {}
+            ListingStatus::Scheduled => env::panic_str("DS: Sale has not started yet"),
+            ListingStatus::Locked => env::panic_str("DS: Sale is being purchased"),
+            _ => env::panic_str("DS: Sale has ended"),
+        }
+        assert_ne!(
+            market_data.owner_id, bidder_id,
+            "DS: Owner cannot bid their own token"
+        );
+        market_data.assert_reserved_for(&bidder_id);
+        let new_bid = Bid {
+            bidder_id: bidder_id.clone(),
+            price: amount.into(),
+            time: current_time,
+            from_bidding_balance,
+        };
+        if let Some(current_bid) = self.internal_top_bid(&contract_and_token_id) {
+            let min_next_bid = auction_rules.min_next_bid(current_bid.price.0);
+
             assert!(
-                current_time >= market_data.started_at.unwrap(),
-                "DS: Sale has not started yet"
+                amount.0 >= min_next_bid,
+                "DS: Can't pay less than the minimum next bid : {:?}",
+                min_next_bid
+            );
+        } else {
+            assert!(
+                amount.0 >= market_data.price,
+                "DS: Can't pay less than starting price: {:?}",
+                market_data.price
             );
         }
-        if market_data.ended_at.is_some() {
+        // a bidder holds one bid per listing, raising it refunds the previous one
+        if let Some(previous_bid) = self.internal_remove_bid(&contract_and_token_id, &bidder_id) {
+            self.internal_release_bid(&market_data.ft_token_id, &previous_bid);
+        } else {
+            let storage_amount = self.storage_minimum_balance().0;
+            let bidder_paid_storage = self.storage_deposits.get(&bidder_id).unwrap_or(0);
+            let bidder_storage_required =
+                (self.internal_storage_count(&bidder_id) + 1) as u128 * storage_amount;
             assert!(
-                current_time <= market_data.ended_at.unwrap(),
-                "DS: Sale has ended"
+                bidder_paid_storage >= bidder_storage_required,
+                "DS: Insufficient storage paid: {}, for {} entries at {} rate of per entry",
+                bidder_paid_storage,
+                bidder_storage_required / storage_amount,
+                storage_amount
             );
+            if self.internal_bid_count(&contract_and_token_id) >= MAX_BIDS_PER_LISTING {
+                let lowest_bid = self.internal_lowest_bid(&contract_and_token_id).unwrap();
+                self.internal_cancel_bid(nft_contract_id.clone(), token_id.clone(), lowest_bid.bidder_id);
+            }
+        }
+        if from_bidding_balance {
+            self.internal_commit_bidding_balance(&bidder_id, &market_data.ft_token_id, amount.0);
         }
-        let remaining_time = market_data.ended_at.unwrap() - current_time;
-        if remaining_time <= FIVE_MINUTES {
-            let extended_ended_at = market_data.ended_at.unwrap() + FIVE_MINUTES;
+        let ended_at = market_data.ended_at.unwrap();
+        let extended_ended_at = auction_rules.extended_ended_at(ended_at, current_time);
+        if extended_ended_at != ended_at {
             market_data.ended_at = Some(extended_ended_at);
+            self.by_ended_at.remove(&(ended_at, contract_and_token_id.clone()));
+            self.by_ended_at.insert(&(extended_ended_at, contract_and_token_id.clone()), &());
 
             env::log_str(
                 &json!({
@@ -178,56 +505,8 @@ impl Marketplace {
                 .to_string(),
             );
         }
-        assert_ne!(
-            market_data.owner_id, bidder_id,
-            "DS: Owner cannot bid their own token"
-        );
-        assert!(
-            env::attached_deposit() >= NearToken::from_yoctonear(amount.into()),
-            "DS: attached deposit is less than amount"
-        );
-        let new_bid = Bid {
-            bidder_id: bidder_id.clone(),
-            price: amount.into(),
-            time: current_time
-        };
-        let mut bids = market_data.bids.unwrap_or(Vec::new());
-        if !bids.is_empty() {
-            let current_bid = &bids[bids.len() - 1];
-
-            assert!(
-                amount.0 >= current_bid.price.0 + (current_bid.price.0 / 100 * 10),
-                "DS: Can't pay less than or equal to current bid price + 10% : {:?}",
-                current_bid.price.0 + (current_bid.price.0 / 100 * 10)
-            );
-            // Retain all elements except account_id
-            bids.retain(|bid| {
-                if bid.bidder_id == bidder_id {
-                    // refund
-                    Promise::new(bid.bidder_id.clone()).transfer(NearToken::from_yoctonear(bid.price.0));
-                }
-                bid.bidder_id != bidder_id
-            });
-        } else {
-            assert!(
-                amount.0 >= market_data.price,
-                "DS: Can't pay less than starting price: {:?}",
-                market_data.price
-            );
-        }
-        market_data.ended_at = Some(current_time + FIVE_MINUTES);
-        bids.push(new_bid);
-        market_data.bids = Some(bids);
+        self.internal_insert_bid(&contract_and_token_id, &new_bid);
         self.market.insert(&contract_and_token_id, &market_data);
-        // Remove first element if bids.length > 50
-        let updated_bids = market_data.bids.unwrap_or(Vec::new());
-        if updated_bids.len() >= 100 {
-            self.internal_cancel_bid(
-                nft_contract_id.clone(),
-                token_id.clone(),
-                updated_bids[0].bidder_id.clone(),
-            )
-        }
         env::log_str(
             &json!({
                 "type": "add_bid",
@@ -252,23 +531,27 @@ impl Marketplace {
     ) {
         assert_one_yocto();
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let market_data = self
+        let mut market_data = self
             .market
             .get(&contract_and_token_id)
             .expect("DS: Token id does not exist");
-
-        let bids = market_data.bids.unwrap();
-
-        assert!(!bids.is_empty(), "DS: Bids data does not exist");
-
-        for x in 0..bids.len() {
-            if bids[x].bidder_id == account_id {
-                assert!(
-                    [bids[x].bidder_id.clone(), self.owner_id.clone()]
-                        .contains(&env::predecessor_account_id()),
-                    "DS: Bidder or owner only"
-                );
-            }
+        let bid = self
+            .bids
+            .get(&format!("{}{}{}", contract_and_token_id, DELIMETER, account_id))
+            .expect("DS: Bids data does not exist");
+        assert!(
+            [account_id.clone(), self.owner_id.clone()]
+                .contains(&env::predecessor_account_id()),
+            "DS: Bidder or owner only"
+        );
+        // once the auction ended the winning bid stays until the sale is settled or declined
+        if self.internal_refresh_status(&mut market_data) == ListingStatus::Ended {
+            let top_bid = self.internal_top_bid(&contract_and_token_id);
+            assert!(
+                top_bid.as_ref().map(|top_bid| &top_bid.bidder_id) != Some(&bid.bidder_id)
+                    || !market_data.is_reserve_met(top_bid.as_ref()),
+                "DS: Winning bid can't be cancelled after the auction ended"
+            );
         }
 
         self.internal_cancel_bid(nft_contract_id, token_id, account_id);
@@ -282,13 +565,11 @@ impl Marketplace {
             .market
             .get(&contract_and_token_id)
             .expect("DS: Token id does not exist");
-        let current_time: u64 = env::block_timestamp();
-
-        let mut bids = market_data.bids.unwrap();
+        let status = self.internal_refresh_status(&mut market_data);
 
-        assert!(!bids.is_empty(), "DS: Cannot accept bid with empty bid");
-
-        let selected_bid = bids.remove(bids.len() - 1);
+        let top_bid = self
+            .internal_top_bid(&contract_and_token_id)
+            .expect("DS: Cannot accept bid with empty bid");
 
         println!(
             "\nAccept Bid Accounts {:?}, {:?}, {:?}",
@@ -300,34 +581,37 @@ impl Marketplace {
             [
                 market_data.owner_id.clone(),
                 self.owner_id.clone(),
-                selected_bid.bidder_id.clone()
+                top_bid.bidder_id.clone()
             ]
             .contains(&env::predecessor_account_id()),
             "DS: Seller, owner or top bidder only"
         );
-        if env::predecessor_account_id() != self.owner_id.clone() && market_data.ended_at.is_some()
-        {
-            assert!(
-                current_time >= market_data.ended_at.unwrap(),
-                "DS: Auction has not ended yet"
-            );
-        }
+        // only the marketplace owner may settle an auction before it ends
+        assert!(
+            status == ListingStatus::Ended
+                || (status == ListingStatus::Active && env::predecessor_account_id() == self.owner_id),
+            "DS: Auction has not ended yet"
+        );
         assert!(
             market_data.end_price.is_none(),
             "DS: Dutch auction does not accept accept_bid"
         );
-        for bid in &bids {
-            Promise::new(bid.bidder_id.clone()).transfer(NearToken::from_yoctonear(bid.price.0));
-        }
-        bids.clear();
-
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+        let selected_bid = match self.internal_pop_funded_bid(&market_data, 0) {
+            Some(bid) => bid,
+            None => return,
+        };
+        assert!(
+            market_data.is_reserve_met(Some(&selected_bid))
+                || env::predecessor_account_id() == market_data.owner_id,
+            "DS: Reserve price not met, only the seller can accept"
+        );
+        // the other bids are refunded when the sale is deleted
         self.internal_process_purchase(
             market_data.nft_contract_id,
             token_id,
             selected_bid.bidder_id.clone(),
             selected_bid.price.clone().0,
+            None,
         );
     }
 
@@ -335,20 +619,16 @@ impl Marketplace {
     pub fn delete_market_data(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
         assert_one_yocto();
         let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let current_time: u64 = env::block_timestamp();
-        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        let mut market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
         assert!(
             [market_data.owner_id.clone(), self.owner_id.clone()]
                 .contains(&env::predecessor_account_id()),
             "DS: Seller or owner only"
         );
-        if market_data.is_auction.is_some() && env::predecessor_account_id() == self.owner_id {
-          assert!(
-            current_time >= market_data.ended_at.unwrap(),
-            "DS: Auction has not ended yet"
-          );
+        if let Some(error) = self.internal_cancel_blocker(&mut market_data) {
+            env::panic_str(error);
         }
-        self.internal_delete_market_data(&nft_contract_id, &token_id);
+        self.internal_delete_market_data(&nft_contract_id, &token_id, ListingStatus::Cancelled);
 
         env::log_str(
             &json!({
@@ -363,6 +643,29 @@ impl Marketplace {
         );
     }
 
+    /// why the sale can't be cancelled right now, None when it can.
+    /// A live auction with bids runs to its end, then it can only be declined below its reserve price
+    fn internal_cancel_blocker(&mut self, market_data: &mut MarketData) -> Option<&'static str> {
+        let contract_and_token_id =
+            format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id);
+        let status = self.internal_refresh_status(market_data);
+        if status == ListingStatus::Locked {
+            return Some("DS: Sale is being purchased");
+        }
+        if let Some(top_bid) = self.internal_top_bid(&contract_and_token_id) {
+            if status != ListingStatus::Ended {
+                return Some("DS: Auction has not ended yet");
+            }
+            if market_data.is_reserve_met(Some(&top_bid)) {
+                return Some("DS: Auction has bids meeting its reserve, use accept_bid");
+            }
+        }
+        if self.internal_has_sealed_bids(&contract_and_token_id) {
+            return Some("DS: Sealed auction has bids, use settle_sealed_auction");
+        }
+        None
+    }
+
     fn internal_cancel_bid(
         &mut self,
         nft_contract_id: AccountId,
@@ -370,21 +673,14 @@ impl Marketplace {
         account_id: AccountId
     ) {
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
-        let mut market_data = self
+        let market_data = self
             .market
             .get(&contract_and_token_id)
             .expect("DS: Token id does not exist");
-        let mut bids = market_data.bids.unwrap();
-
-        assert!(!bids.is_empty(), "DS: Bids data does not exist");
-        for x in 0..bids.len() {
-            if bids[x].bidder_id == account_id {
-                Promise::new(bids[x].bidder_id.clone()).transfer(NearToken::from_yoctonear(bids[x].price.0));
-            }
-        }
-        bids.retain(|bid| bid.bidder_id != account_id);
-        market_data.bids = Some(bids);
-        self.market.insert(&contract_and_token_id, &market_data);
+        let bid = self
+            .internal_remove_bid(&contract_and_token_id, &account_id)
+            .expect("DS: Bids data does not exist");
+        self.internal_release_bid(&market_data.ft_token_id, &bid);
 
         env::log_str(
             &json!({
@@ -402,19 +698,33 @@ impl Marketplace {
         nft_contract_id: AccountId,
         token_id: TokenId,
         buyer_id: AccountId,
-        price: u128
+        price: u128,
+        keeper_id: Option<AccountId>,
     ) -> Promise {
-        let market_data = self
-            .internal_delete_market_data(&nft_contract_id, &token_id)
-            .expect("DS: Sale does not exist");
-        ext_contract::ext(nft_contract_id)
+        let market_data = self.internal_lock_listing(&nft_contract_id, &token_id, &buyer_id, price);
+        // sellers and buyers settling their own sale are not rewarded
+        let keeper_id = keeper_id.filter(|keeper_id| *keeper_id != market_data.owner_id && *keeper_id != buyer_id);
+        self.internal_check_purchase_approval(market_data, buyer_id, price, keeper_id)
+    }
+
+    /// transfers the sold token to the buyer, resolve_purchase pays for it
+    pub(crate) fn internal_transfer_purchase(
+        &self,
+        market_data: MarketData,
+        buyer_id: AccountId,
+        price: u128,
+        keeper_id: Option<AccountId>,
+    ) -> Promise {
+        ext_contract::ext(market_data.nft_contract_id.clone())
             .with_attached_deposit(ONE_YOCTONEAR)
             .with_static_gas(GAS_FOR_NFT_TRANSFER)
             .nft_transfer_payout(
                 buyer_id.clone(), 
-                token_id, 
+                market_data.token_id.clone(), 
                 Some(market_data.approval_id),
-                Some(price.into()),
+                None,
+                price.into(),
+                Some(self.max_len_payout),
             )
         .then(
             Self::ext(env::current_account_id())
@@ -422,7 +732,8 @@ impl Marketplace {
             .resolve_purchase(
                 buyer_id,
                 market_data,
-                price.into()
+                price.into(),
+                keeper_id
             )
         )
     }
@@ -432,74 +743,33 @@ impl Marketplace {
         &mut self,
         buyer_id: AccountId,
         market_data: MarketData,
-        price: U128
+        price: U128,
+        keeper_id: Option<AccountId>,
     ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            let parsed_payout = near_sdk::serde_json::from_slice::<PayoutHashMap>(&value);
-            if parsed_payout.is_err() {
-                near_sdk::serde_json::from_slice::<Payout>(&value)
-                    .ok()
-                    .and_then(|payout| {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 100 {
-                            Some(payout.payout)
-                        } else {
-                            None
-                        }
-                    })
-            } else {
-                parsed_payout.ok().and_then(|payout| {
-                    let mut remainder = price.0;
-                    for &value in payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 100 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
-            }
-        });
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if !is_promise_success() {
-                Promise::new(buyer_id.clone()).transfer(NearToken::from_yoctonear(u128::from(price.0)));
-            } else {
-                let treasury_fee: u128 = price.0 * self.transaction_fee as u128 / 10_000u128;
-                Promise::new(market_data.owner_id.clone()).transfer(NearToken::from_yoctonear(price.0 - treasury_fee));
-                if treasury_fee > 0 {
-                    Promise::new(self.treasury_id.clone()).transfer(NearToken::from_yoctonear(treasury_fee));
-                }
-                env::log_str(
-                    &json!({
-                        "type": "resolve_purchase",
-                        "params": {
-                            "owner_id": &market_data.owner_id,
-                            "nft_contract_id": &market_data.nft_contract_id,
-                            "token_id": &market_data.token_id,
-                            "price": price,
-                            "buyer_id": buyer_id,
-                        }
-                    })
-                    .to_string(),
+        if !is_promise_success() {
+            self.internal_credit_pending(market_data.ft_token_id.clone(), buyer_id.clone(), price.0);
+            self.internal_restore_listing(&market_data);
+            return price
+        }
+        self.internal_settle_locked_listing(&market_data);
+        let payout = promise_result_as_success()
+            .and_then(|value| self.internal_split_payout(&value, price.0, &market_data.owner_id));
+        let payout = match payout {
+            Some(payout) => payout,
+            None => {
+                self.internal_credit_pending(market_data.ft_token_id.clone(), buyer_id.clone(), price.0);
+                log_payout_rejected(
+                    &market_data.owner_id,
+                    &market_data.nft_contract_id,
+                    &market_data.token_id,
+                    &buyer_id,
+                    &market_data.ft_token_id,
+                    price.0,
                 );
+                return price;
             }
-            return price
         };
-        let treasury_fee: u128 = price.0 * self.transaction_fee as u128 / 10_000u128;
-        for (receiver_id, amount) in payout {
-            if receiver_id == market_data.owner_id {
-                Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount.0 - treasury_fee));
-                Promise::new(self.treasury_id.clone()).transfer(NearToken::from_yoctonear(treasury_fee));
-            } else {
-                Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount.0));
-            }
-        }
+        self.internal_credit_sale(&market_data.ft_token_id, &market_data.owner_id, price.0, payout, keeper_id);
         env::log_str(
             &json!({
                 "type": "resolve_purchase",
@@ -508,6 +778,7 @@ impl Marketplace {
                     "nft_contract_id": &market_data.nft_contract_id,
                     "token_id": &market_data.token_id,
                     "price": price,
+                    "ft_token_id": &market_data.ft_token_id,
                     "buyer_id": buyer_id,
                 }
             })
@@ -516,29 +787,48 @@ impl Marketplace {
         return price
     }
     
+    /// lists the token on the sale terms of `args`, its market_type, buyer_id and bundle_id are not used here
     fn internal_add_market_data(
         &mut self,
         owner_id: AccountId,
         approval_id: u64,
         nft_contract_id: AccountId,
         token_id: TokenId,
-        price: U128,
-        mut started_at: Option<U64>,
-        ended_at: Option<U64>,
-        end_price: Option<U128>,
-        is_auction: Option<bool>,
-    ) {
+        args: MarketArgs,
+    ) -> MarketData {
+        let MarketArgs {
+            price,
+            mut started_at,
+            ended_at,
+            is_auction,
+            end_price,
+            ft_token_id,
+            price_curve,
+            auction_rules,
+            reserve_price,
+            buy_now_price,
+            sealed_bid_rules,
+            reserved_for,
+            reserved_until,
+            holder_gate,
+            ..
+        } = args;
+        let price = price.expect("DS: price not specified");
+        self.internal_assert_not_bundled(&nft_contract_id, &token_id);
+        assert!(
+            reserved_for.is_some() || reserved_until.is_none(),
+            "DS: reserved_until needs reserved_for"
+        );
+        let reservation = reserved_for.map(|reserved_for| Reservation::new(reserved_for, reserved_until));
         let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
-        let bids: Option<Bids> = match is_auction {
-            Some(u) => {
-                if u {
-                    Some(Vec::new())
-                } else {
-                    None
-                }
+        // approving again replaces the sale, which has to be cancellable like with delete_market_data
+        if let Some(mut market_data) = self.market.get(&contract_and_token_id) {
+            if let Some(error) = self.internal_cancel_blocker(&mut market_data) {
+                env::panic_str(error);
             }
-            None => None,
-        };
+        }
+        // drop the old sale from every index first
+        self.internal_delete_market_data(&nft_contract_id, &token_id, ListingStatus::Cancelled);
         let current_time: u64 = env::block_timestamp();
         if started_at.is_some() {
             // if start time is behind that current time, makes it current time
@@ -574,31 +864,96 @@ impl Marketplace {
                 end_price.unwrap().0 < price.0,
                 "DS: End price is more than starting price"
             );
+            // the price of a dutch auction is interpolated between its start and end
+            if started_at.is_none() {
+                started_at = Some(U64(current_time));
+            }
+            assert!(ended_at.is_some(), "DS: Ended at is none");
         }
-        self.market.insert(
-            &contract_and_token_id,
-            &MarketData {
-                owner_id: owner_id.clone().into(),
-                approval_id,
-                nft_contract_id: nft_contract_id.clone().into(),
-                token_id: token_id.clone(),
-                price: price.into(),
-                bids: bids,
-                started_at: match started_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                ended_at: match ended_at {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                end_price: match end_price {
-                    Some(x) => Some(x.0),
-                    None => None,
-                },
-                is_auction: is_auction,
+        if let Some(price_curve) = &price_curve {
+            assert!(
+                is_auction.is_some() && end_price.is_some(),
+                "DS: price_curve is only for dutch auctions"
+            );
+            price_curve.assert_valid(started_at.unwrap().0, ended_at.unwrap().0);
+        }
+        if let Some(sealed_bid_rules) = &sealed_bid_rules {
+            assert!(
+                is_auction == Some(true) && end_price.is_none(),
+                "DS: sealed_bid_rules are only for auctions without end_price"
+            );
+            sealed_bid_rules.assert_valid(started_at.unwrap().0, ended_at.unwrap().0);
+        }
+        if let Some(holder_gate) = &holder_gate {
+            // sealed bids are committed without going through buy or add_bid
+            assert!(sealed_bid_rules.is_none(), "DS: holder_gate is not supported on sealed auctions");
+            holder_gate.assert_valid();
+        }
+        let auction_rules = if is_auction == Some(true) && end_price.is_none() && sealed_bid_rules.is_none() {
+            let auction_rules = auction_rules.unwrap_or_default();
+            auction_rules.assert_valid(ended_at.unwrap().0);
+            Some(auction_rules)
+        } else {
+            assert!(auction_rules.is_none(), "DS: auction_rules are only for english auctions");
+            None
+        };
+        if auction_rules.is_none() {
+            assert!(
+                reserve_price.is_none() && buy_now_price.is_none(),
+                "DS: reserve_price and buy_now_price are only for english auctions"
+            );
+        }
+        if let Some(reserve_price) = reserve_price {
+            assert!(
+                reserve_price.0 >= price.0,
+                "DS: Reserve price is less than starting price"
+            );
+        }
+        if let Some(buy_now_price) = buy_now_price {
+            assert!(
+                buy_now_price.0 > price.0 && buy_now_price.0 >= reserve_price.map_or(0, |x| x.0),
+                "DS: Buy now price must be above the starting and reserve price"
+            );
+        }
+        let market_data = MarketData {
+            owner_id: owner_id.clone().into(),
+            approval_id,
+            nft_contract_id: nft_contract_id.clone().into(),
+            token_id: token_id.clone(),
+            price: price.into(),
+            started_at: match started_at {
+                Some(x) => Some(x.0),
+                None => None,
             },
-        );
+            ended_at: match ended_at {
+                Some(x) => Some(x.0),
+                None => None,
+            },
+            end_price: match end_price {
+                Some(x) => Some(x.0),
+                None => None,
+            },
+            is_auction: is_auction,
+            ft_token_id,
+            price_curve,
+            auction_rules,
+            reserve_price: reserve_price.map(|x| x.0),
+            buy_now_price: buy_now_price.map(|x| x.0),
+            sealed_bid_rules,
+            status: if started_at.is_some_and(|started_at| started_at.0 > current_time) {
+                ListingStatus::Scheduled
+            } else {
+                ListingStatus::Active
+            },
+            reservation,
+            holder_gate,
+            lock: None,
+        };
+        self.market.insert(&contract_and_token_id, &market_data);
+        self.internal_add_to_price_index(&market_data);
+        if let Some(ended_at) = market_data.ended_at {
+            self.by_ended_at.insert(&(ended_at, contract_and_token_id.clone()), &());
+        }
         let mut token_ids = self.by_owner_id.get(&owner_id).unwrap_or_else(|| {
             UnorderedSet::new(
                 StorageKey::ByOwnerIdInner {
@@ -608,36 +963,57 @@ impl Marketplace {
         });
         token_ids.insert(&contract_and_token_id);
         self.by_owner_id.insert(&owner_id, &token_ids);
-        env::log_str(
-            &json!({
-                "type": "add_market_data",
-                "params": {
-                    "owner_id": owner_id,
-                    "approval_id": approval_id,
-                    "nft_contract_id": nft_contract_id,
-                    "token_id": token_id,
-                    "price": price,
-                    "started_at": started_at,
-                    "ended_at": ended_at,
-                    "end_price": end_price,
-                    "is_auction": is_auction,
+
+        let mut by_nft_contract_id = self.by_nft_contract_id.get(&nft_contract_id).unwrap_or_else(|| {
+            UnorderedSet::new(
+                StorageKey::ByNFTContractIdInner {
+                    account_id_hash: hash_account_id(&nft_contract_id),
                 }
-            })
-            .to_string(),
-        );
+            )
+        });
+        by_nft_contract_id.insert(&token_id);
+        self.by_nft_contract_id.insert(&nft_contract_id, &by_nft_contract_id);
+        market_data
     }
 
+    /// removes the sale, if any, and records `status` as how it ended. Open and sealed bids still on it are refunded
     fn internal_delete_market_data(
         &mut self,
         nft_contract_id: &AccountId,
         token_id: &TokenId,
+        status: ListingStatus,
+    ) -> Option<MarketData> {
+        let market_data = self.internal_remove_market_data(nft_contract_id, token_id, status);
+        if let Some(market_data) = &market_data {
+            log_listing_status(market_data, status);
+        }
+        market_data
+    }
+
+    /// internal_delete_market_data without the update_listing_status event, for callers that log it themselves
+    fn internal_remove_market_data(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        status: ListingStatus,
     ) -> Option<MarketData> {
         let contract_and_token_id = format!("{}{}{}", &nft_contract_id, DELIMETER, token_id);
         let market_data = self.market.get(&contract_and_token_id);
         if market_data.is_some() {
             self.market.remove(&contract_and_token_id);
         }
-        market_data.map(|market_data| {
+        market_data.map(|mut market_data| {
+            market_data.status = status;
+            for bid in self.internal_remove_bids(&contract_and_token_id) {
+                self.internal_release_bid(&market_data.ft_token_id, &bid);
+            }
+            for sealed_bid in self.internal_remove_sealed_bids(&contract_and_token_id) {
+                self.internal_credit_pending(market_data.ft_token_id.clone(), sealed_bid.bidder_id, sealed_bid.deposit.0);
+            }
+            self.internal_remove_from_price_index(&market_data);
+            if let Some(ended_at) = market_data.ended_at {
+                self.by_ended_at.remove(&(ended_at, contract_and_token_id.clone()));
+            }
             let by_owner_id = self.by_owner_id.get(&market_data.owner_id);
             if let Some(mut by_owner_id) = by_owner_id {
                 by_owner_id.remove(&contract_and_token_id);
@@ -647,6 +1023,15 @@ impl Marketplace {
                     self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
                 }
             }
+            let by_nft_contract_id = self.by_nft_contract_id.get(nft_contract_id);
+            if let Some(mut by_nft_contract_id) = by_nft_contract_id {
+                by_nft_contract_id.remove(token_id);
+                if by_nft_contract_id.is_empty() {
+                    self.by_nft_contract_id.remove(nft_contract_id);
+                } else {
+                    self.by_nft_contract_id.insert(nft_contract_id, &by_nft_contract_id);
+                }
+            }
             market_data
         })
     }
@@ -673,8 +1058,7 @@ impl Marketplace {
         assert_one_yocto();
         let owner_id = env::predecessor_account_id();
         let mut amount = self.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let market_data_owner = self.by_owner_id.get(&owner_id);
-        let len = market_data_owner.map(|s| s.len()).unwrap_or_default();
+        let len = self.internal_storage_count(&owner_id);
         let diff = u128::from(len) * STORAGE_ADD_MARKET_DATA;
         amount -= diff;
         if amount > 0 {
@@ -693,6 +1077,17 @@ impl Marketplace {
             .map_or(0, |by_owner_id| by_owner_id.len())
             .into()
     }
+
+    /// number of sales, offers and bids covered by account_id's storage deposit
+    fn internal_storage_count(&self, account_id: &AccountId) -> u64 {
+        self.get_supply_by_owner_id(account_id.clone()).0
+            + self.get_supply_offers_by_bidder_id(account_id.clone()).0
+            + self.get_supply_collection_offers_by_bidder_id(account_id.clone()).0
+            + self.get_supply_bids_by_bidder_id(account_id.clone()).0
+            + self.get_supply_bundles_by_owner_id(account_id.clone()).0
+            + self.get_supply_swaps_by_proposer_id(account_id.clone()).0
+            + self.get_supply_sealed_bids_by_bidder_id(account_id.clone()).0
+    }
     #[payable]
     pub fn set_treasury(&mut self, treasury_id: AccountId) {
         assert_one_yocto();
@@ -707,6 +1102,25 @@ impl Marketplace {
         self.transaction_fee = fee;
     }
 
+    #[payable]
+    pub fn set_keeper_reward_bps(&mut self, keeper_reward_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(keeper_reward_bps <= 10_000, "DS: keeper_reward_bps can't be above 10000");
+        self.keeper_reward_bps = keeper_reward_bps;
+    }
+
+    #[payable]
+    pub fn set_bidding_commitment_bps(&mut self, bidding_commitment_bps: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            bidding_commitment_bps >= 10_000,
+            "DS: bidding_commitment_bps can't be below 10000"
+        );
+        self.bidding_commitment_bps = bidding_commitment_bps;
+    }
+
     #[payable]
     pub fn transfer_ownership(&mut self, owner_id: AccountId) {
         assert_one_yocto();
@@ -728,11 +1142,30 @@ impl Marketplace {
         remove_accounts(Some(nft_contract_ids), &mut self.approved_nft_contract_ids);
     }
 
+    // Approved fungible tokens
+    #[payable]
+    pub fn add_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        add_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+    }
+
+    #[payable]
+    pub fn remove_approved_ft_token_ids(&mut self, ft_token_ids: Vec<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        remove_accounts(Some(ft_token_ids), &mut self.approved_ft_token_ids);
+    }
+
     pub fn get_config(&self) -> MarketplaceConfig {
         MarketplaceConfig {
             owner_id: self.owner_id.clone(),
             treasury_id: self.treasury_id.clone(),
-            transaction_fee: self.transaction_fee
+            transaction_fee: self.transaction_fee,
+            bidding_commitment_bps: self.bidding_commitment_bps,
+            keeper_reward_bps: self.keeper_reward_bps,
+            max_len_payout: self.max_len_payout,
+            max_royalty_bps: self.max_royalty_bps,
         }
     }
 
@@ -740,28 +1173,108 @@ impl Marketplace {
         self.approved_nft_contract_ids.to_vec()
     }
 
+    pub fn approved_ft_token_ids(&self) -> Vec<AccountId> {
+        self.approved_ft_token_ids.to_vec()
+    }
+
     pub fn get_market_data(self, nft_contract_id: AccountId, token_id: TokenId) -> MarketDataJson {
         let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
         let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        self.internal_market_data_json(market_data)
+    }
 
-        let mut price = market_data.price;
+    pub fn get_market_data_paginated(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<MarketDataJson> {
+        self.market
+            .values_as_vector()
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
 
-        if market_data.is_auction.is_some() && market_data.end_price.is_some() {
-            let current_time = env::block_timestamp();
-            let end_price = market_data.end_price.unwrap();
-            let started_at = market_data.started_at.unwrap();
-            let ended_at = market_data.ended_at.unwrap();
+    pub fn get_sales_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let by_owner_id = match self.by_owner_id.get(&account_id) {
+            Some(by_owner_id) => by_owner_id,
+            None => return vec![],
+        };
+        by_owner_id
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|contract_and_token_id| self.market.get(&contract_and_token_id))
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
 
-            if current_time < started_at {
-                // Use current market_data.price
-            } else if current_time > ended_at {
-                price = end_price;
-            } else {
-                let time_since_start = current_time - started_at;
-                let duration = ended_at - started_at;
-                price = price - ((price - end_price) / duration as u128) * time_since_start as u128;
-            }
+    pub fn get_sales_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<MarketDataJson> {
+        let by_nft_contract_id = match self.by_nft_contract_id.get(&nft_contract_id) {
+            Some(by_nft_contract_id) => by_nft_contract_id,
+            None => return vec![],
+        };
+        by_nft_contract_id
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|token_id| {
+                self.market.get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            })
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
+
+    pub fn get_supply_sales(&self) -> U64 {
+        U64(self.market.len())
+    }
+
+    pub fn get_supply_by_nft_contract_id(&self, nft_contract_id: AccountId) -> U64 {
+        self.by_nft_contract_id
+            .get(&nft_contract_id)
+            .map_or(0, |by_nft_contract_id| by_nft_contract_id.len())
+            .into()
+    }
+
+    /// price the sale would have at `timestamp`, lets buyers preview where a dutch auction is heading
+    pub fn get_price_at(&self, nft_contract_id: AccountId, token_id: TokenId, timestamp: U64) -> U128 {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        self.internal_price_at(&market_data, timestamp.0).into()
+    }
+
+    /// price a buyer would pay right now
+    fn internal_current_price(&self, market_data: &MarketData) -> u128 {
+        self.internal_price_at(market_data, env::block_timestamp())
+    }
+
+    /// a sale without the times of a dutch auction stays at its price
+    fn internal_price_at(&self, market_data: &MarketData, timestamp: u64) -> u128 {
+        match (market_data.end_price, market_data.started_at, market_data.ended_at) {
+            (Some(end_price), Some(started_at), Some(ended_at)) => market_data
+                .price_curve
+                .clone()
+                .unwrap_or(PriceCurve::Linear)
+                .price_at(market_data.price, end_price, started_at, ended_at, timestamp),
+            _ => market_data.price,
         }
+    }
+
+    fn internal_market_data_json(&self, market_data: MarketData) -> MarketDataJson {
+        let price = self.internal_current_price(&market_data);
+        let contract_and_token_id =
+            format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id);
+        let top_bid = self.internal_top_bid(&contract_and_token_id);
+        let reserve_met = market_data.reserve_price.map(|_| market_data.is_reserve_met(top_bid.as_ref()));
+        let status = market_data.status_at(env::block_timestamp());
 
         MarketDataJson {
             owner_id: market_data.owner_id,
@@ -769,11 +1282,64 @@ impl Marketplace {
             nft_contract_id: market_data.nft_contract_id,
             token_id: market_data.token_id,
             price: price.into(),
-            bids: market_data.bids,
+            top_bid,
+            bid_count: self.internal_bid_count(&contract_and_token_id).into(),
             started_at: market_data.started_at.map(|x| x.into()),
             ended_at: market_data.ended_at.map(|x| x.into()),
             end_price: market_data.end_price.map(|x| x.into()),
             is_auction: market_data.is_auction,
+            ft_token_id: market_data.ft_token_id,
+            price_curve: market_data.price_curve,
+            auction_rules: market_data.auction_rules,
+            reserve_met,
+            buy_now_price: market_data.buy_now_price.map(|x| x.into()),
+            sealed_bid_rules: market_data.sealed_bid_rules,
+            status,
+            reservation: market_data.reservation,
+            holder_gate: market_data.holder_gate,
+        }
+    }
+
+    /// pays the proceeds of a transferred token out to the seller and the royalty receivers of its payout.
+    /// The marketplace fee comes out of the seller's share
+    fn internal_credit_sale(
+        &mut self,
+        ft_token_id: &Option<AccountId>,
+        owner_id: &AccountId,
+        price: u128,
+        payout: PayoutSplit,
+        keeper_id: Option<AccountId>,
+    ) {
+        // royalties may leave the seller less than the fee
+        let treasury_fee: u128 = (price * self.transaction_fee as u128 / 10_000u128).min(payout.seller_amount);
+        self.internal_credit_pending(ft_token_id.clone(), owner_id.clone(), payout.seller_amount - treasury_fee);
+        self.internal_credit_fee(ft_token_id.clone(), treasury_fee, keeper_id);
+        for (receiver_id, amount) in payout.royalties {
+            self.internal_credit_pending(ft_token_id.clone(), receiver_id, amount);
+        }
+    }
+
+    /// splits the marketplace fee of a sale between the treasury and the keeper that settled it, if any
+    fn internal_credit_fee(&mut self, ft_token_id: Option<AccountId>, treasury_fee: u128, keeper_id: Option<AccountId>) {
+        let keeper_reward = match keeper_id {
+            Some(keeper_id) => {
+                let keeper_reward = treasury_fee * self.keeper_reward_bps as u128 / 10_000u128;
+                self.internal_credit_pending(ft_token_id.clone(), keeper_id, keeper_reward);
+                keeper_reward
+            }
+            None => 0,
+        };
+        self.internal_credit_pending(ft_token_id, self.treasury_id.clone(), treasury_fee - keeper_reward);
+    }
+
+    /// sends `amount` to `receiver_id` in NEAR, or in `ft_token_id` when the sale is priced in a fungible token
+    pub(crate) fn internal_transfer(&self, ft_token_id: Option<AccountId>, receiver_id: AccountId, amount: u128) -> Promise {
+        match ft_token_id {
+            Some(ft_token_id) => ext_fungible_token::ext(ft_token_id)
+                .with_attached_deposit(ONE_YOCTONEAR)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(receiver_id, amount.into(), None),
+            None => Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount)),
         }
     }
 
@@ -800,6 +1366,45 @@ fn remove_accounts(accounts: Option<Vec<AccountId>>, set: &mut UnorderedSet<Acco
         })
     });
 }
+/// books and price indexes are kept per collection and currency, "near" when priced in NEAR
+pub fn collection_and_currency_id(nft_contract_id: &AccountId, ft_token_id: &Option<AccountId>) -> String {
+    format!(
+        "{}{}{}",
+        nft_contract_id,
+        DELIMETER,
+        ft_token_id.as_ref().map_or("near", |ft_token_id| ft_token_id.as_str())
+    )
+}
+
+pub fn log_add_market_data(market_data: &MarketData) {
+    env::log_str(
+        &json!({
+            "type": "add_market_data",
+            "params": {
+                "owner_id": market_data.owner_id,
+                "approval_id": market_data.approval_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "price": U128(market_data.price),
+                "started_at": market_data.started_at.map(U64),
+                "ended_at": market_data.ended_at.map(U64),
+                "end_price": market_data.end_price.map(U128),
+                "is_auction": market_data.is_auction,
+                "ft_token_id": market_data.ft_token_id,
+                "price_curve": market_data.price_curve,
+                "auction_rules": market_data.auction_rules,
+                "has_reserve_price": market_data.reserve_price.is_some(),
+                "buy_now_price": market_data.buy_now_price.map(U128),
+                "sealed_bid_rules": market_data.sealed_bid_rules,
+                "status": market_data.status,
+                "reservation": market_data.reservation,
+                "holder_gate": market_data.holder_gate,
+            }
+        })
+        .to_string(),
+    );
+}
+
 pub fn hash_account_id(account_id: &AccountId) -> CryptoHash {
     let mut hash = CryptoHash::default();
     hash.copy_from_slice(&env::sha256(account_id.as_bytes()));
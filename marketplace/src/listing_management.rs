@@ -0,0 +1,225 @@
+use crate::*;
+
+/// what a delete_all_my_listings call did
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeletedListings {
+    pub deleted: U64,
+    pub has_more: bool,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// changes a sale in place, keeping its approval. `ended_at` and `end_price` keep their value when None,
+    /// end_price only applies to dutch auctions and auctions can only be changed before the first bid
+    #[payable]
+    pub fn update_market_data(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        price: U128,
+        ended_at: Option<U64>,
+        end_price: Option<U128>,
+    ) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        assert_eq!(
+            market_data.owner_id,
+            env::predecessor_account_id(),
+            "DS: Seller only"
+        );
+        assert!(
+            matches!(
+                self.internal_refresh_status(&mut market_data),
+                ListingStatus::Scheduled | ListingStatus::Active
+            ),
+            "DS: Sale is not active"
+        );
+
+        let new_ended_at = ended_at.map(|x| x.0).or(market_data.ended_at);
+        if let Some(new_ended_at) = new_ended_at {
+            assert!(
+                new_ended_at > env::block_timestamp()
+                    && market_data.started_at.is_none_or(|started_at| new_ended_at > started_at),
+                "DS: ended_at must be in the future and after started_at"
+            );
+        }
+
+        if let Some(current_end_price) = market_data.end_price {
+            let new_end_price = end_price.map_or(current_end_price, |x| x.0);
+            assert!(
+                new_end_price < price.0,
+                "DS: End price is more than starting price"
+            );
+            if let Some(price_curve) = &market_data.price_curve {
+                price_curve.assert_valid(market_data.started_at.unwrap(), new_ended_at.unwrap());
+            }
+            market_data.end_price = Some(new_end_price);
+        } else {
+            assert!(end_price.is_none(), "DS: end_price is only for dutch auctions");
+        }
+
+        if market_data.auction_rules.is_some() || market_data.sealed_bid_rules.is_some() {
+            assert!(
+                self.internal_top_bid(&contract_and_token_id).is_none()
+                    && !self.internal_has_sealed_bids(&contract_and_token_id),
+                "DS: Auction with bids can't be updated"
+            );
+            if let Some(auction_rules) = &market_data.auction_rules {
+                auction_rules.assert_valid(new_ended_at.unwrap());
+            }
+            if let Some(sealed_bid_rules) = &market_data.sealed_bid_rules {
+                sealed_bid_rules.assert_valid(market_data.started_at.unwrap(), new_ended_at.unwrap());
+            }
+            if let Some(reserve_price) = market_data.reserve_price {
+                assert!(
+                    reserve_price >= price.0,
+                    "DS: Reserve price is less than starting price"
+                );
+            }
+            if let Some(buy_now_price) = market_data.buy_now_price {
+                assert!(
+                    buy_now_price > price.0,
+                    "DS: Buy now price must be above the starting and reserve price"
+                );
+            }
+        }
+
+        self.internal_remove_from_price_index(&market_data);
+        if let Some(ended_at) = market_data.ended_at {
+            self.by_ended_at.remove(&(ended_at, contract_and_token_id.clone()));
+        }
+        market_data.price = price.0;
+        market_data.ended_at = new_ended_at;
+        if let Some(ended_at) = market_data.ended_at {
+            self.by_ended_at.insert(&(ended_at, contract_and_token_id.clone()), &());
+        }
+        self.internal_add_to_price_index(&market_data);
+        self.market.insert(&contract_and_token_id, &market_data);
+
+        env::log_str(
+            &json!({
+                "type": "update_market_data",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "price": price,
+                    "ended_at": market_data.ended_at.map(U64),
+                    "end_price": market_data.end_price.map(U128),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// cancels several sales of a collection at once, every one of them has to be cancellable
+    #[payable]
+    pub fn delete_market_data_batch(&mut self, nft_contract_id: AccountId, token_ids: Vec<TokenId>) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut deleted = Vec::with_capacity(token_ids.len());
+        for token_id in token_ids {
+            let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+            let mut market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+            assert!(
+                [market_data.owner_id.clone(), self.owner_id.clone()].contains(&account_id),
+                "DS: Seller or owner only"
+            );
+            if let Some(error) = self.internal_cancel_blocker(&mut market_data) {
+                env::panic_str(error);
+            }
+            self.internal_remove_market_data(&nft_contract_id, &token_id, ListingStatus::Cancelled);
+            deleted.push(market_data);
+        }
+        log_delete_market_data_batch(&account_id, &deleted);
+    }
+
+    /// cancels the caller's sales in a page of up to `limit` of them, skipping the ones that can't be
+    /// cancelled yet. `has_more` tells whether another call can cancel more, it is false once the page
+    /// made no progress since the sales left at its front are all blocked
+    #[payable]
+    pub fn delete_all_my_listings(&mut self, limit: Option<u64>) -> DeletedListings {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        let limit = limit.unwrap_or(50) as usize;
+        let contract_and_token_ids: Vec<ContractAndTokenId> = match self.by_owner_id.get(&owner_id) {
+            Some(by_owner_id) => by_owner_id.iter().take(limit).collect(),
+            None => vec![],
+        };
+        let mut deleted = Vec::new();
+        let mut blocked = 0;
+        for contract_and_token_id in contract_and_token_ids {
+            let mut market_data = match self.market.get(&contract_and_token_id) {
+                Some(market_data) => market_data,
+                None => continue,
+            };
+            if self.internal_cancel_blocker(&mut market_data).is_some() {
+                blocked += 1;
+                continue;
+            }
+            self.internal_remove_market_data(
+                &market_data.nft_contract_id,
+                &market_data.token_id,
+                ListingStatus::Cancelled,
+            );
+            deleted.push(market_data);
+        }
+        let remaining = self.by_owner_id.get(&owner_id).map_or(0, |by_owner_id| by_owner_id.len());
+        log_delete_market_data_batch(&owner_id, &deleted);
+        DeletedListings {
+            deleted: U64(deleted.len() as u64),
+            has_more: !deleted.is_empty() && remaining > blocked,
+        }
+    }
+}
+
+/// also stands for the update_listing_status event of every listing in it
+fn log_delete_market_data_batch(account_id: &AccountId, deleted: &[MarketData]) {
+    if deleted.is_empty() {
+        return;
+    }
+    env::log_str(
+        &json!({
+            "type": "delete_market_data_batch",
+            "params": {
+                "account_id": account_id,
+                "status": ListingStatus::Cancelled,
+                "listings": deleted
+                    .iter()
+                    .map(|market_data| json!({
+                        "owner_id": market_data.owner_id,
+                        "nft_contract_id": market_data.nft_contract_id,
+                        "token_id": market_data.token_id,
+                    }))
+                    .collect::<Vec<_>>(),
+            }
+        })
+        .to_string(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn deleting_all_listings_goes_a_page_at_a_time() {
+        let mut contract = new_marketplace();
+        for token_id in ["1", "2", "3"] {
+            list(&mut contract, "seller.near", token_id, fixed_price(100));
+        }
+
+        set_context("seller.near", 1, 0);
+        let page = contract.delete_all_my_listings(Some(2));
+        assert_eq!(page.deleted, U64(2));
+        assert!(page.has_more);
+
+        let page = contract.delete_all_my_listings(Some(2));
+        assert_eq!(page.deleted, U64(1));
+        assert!(!page.has_more);
+        assert!(["1", "2", "3"].iter().all(|token_id| market_data_of(&contract, token_id).is_none()));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::*;
+
+const GAS_FOR_RESOLVE_RESTORE_LISTING: Gas = Gas::from_tgas(10);
+/// purchases resolve within a few blocks, a listing locked for longer lost its callback
+pub const LISTING_LOCK_TIMEOUT: u64 = 3_600_000_000_000;
+
+/// where a listing is in its lifecycle. Scheduled, Active and Ended follow started_at and ended_at
+/// and are stored when the listing is next touched, Locked holds it while a purchase transfers the token,
+/// Settled and Cancelled are final and remove it
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum ListingStatus {
+    Scheduled,
+    Active,
+    Ended,
+    Locked,
+    Settled,
+    Cancelled,
+}
+
+/// the purchase a Locked listing is held for, `escrow` is what the marketplace holds for the buyer
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ListingLock {
+    pub buyer_id: AccountId,
+    pub escrow: u128,
+    pub locked_at: u64,
+}
+
+impl MarketData {
+    pub fn status_at(&self, timestamp: u64) -> ListingStatus {
+        match self.status {
+            ListingStatus::Scheduled | ListingStatus::Active => {
+                if self.ended_at.is_some_and(|ended_at| timestamp > ended_at) {
+                    ListingStatus::Ended
+                } else if self.started_at.is_some_and(|started_at| timestamp < started_at) {
+                    ListingStatus::Scheduled
+                } else {
+                    ListingStatus::Active
+                }
+            }
+            status => status,
+        }
+    }
+}
+
+impl Marketplace {
+    /// brings the stored status of a listing up to the current time and returns it
+    pub(crate) fn internal_refresh_status(&mut self, market_data: &mut MarketData) -> ListingStatus {
+        let status = market_data.status_at(env::block_timestamp());
+        if status != market_data.status {
+            // a listing untouched through its whole run still passes through Active
+            if market_data.status == ListingStatus::Scheduled && status == ListingStatus::Ended {
+                log_listing_status(market_data, ListingStatus::Active);
+            }
+            market_data.status = status;
+            self.market.insert(
+                &format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id),
+                market_data,
+            );
+            log_listing_status(market_data, status);
+        }
+        status
+    }
+
+    /// takes a listing out of trading while its token is transferred to the buyer
+    pub(crate) fn internal_lock_listing(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        buyer_id: &AccountId,
+        escrow: u128,
+    ) -> MarketData {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = self.market.get(&contract_and_token_id).expect("DS: Sale does not exist");
+        assert_ne!(market_data.status, ListingStatus::Locked, "DS: Sale is being purchased");
+        market_data.status = ListingStatus::Locked;
+        market_data.lock = Some(ListingLock {
+            buyer_id: buyer_id.clone(),
+            escrow,
+            locked_at: env::block_timestamp(),
+        });
+        self.market.insert(&contract_and_token_id, &market_data);
+        log_listing_status(&market_data, ListingStatus::Locked);
+        market_data
+    }
+
+    /// removes the listing locked for a transfer that went through
+    pub(crate) fn internal_settle_locked_listing(&mut self, market_data: &MarketData) {
+        if self.internal_is_locked(market_data) {
+            self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id, ListingStatus::Settled);
+        }
+    }
+
+    /// puts the listing locked for a failed transfer back on sale,
+    /// unless the marketplace's approval turns out to be revoked
+    pub(crate) fn internal_restore_listing(&mut self, market_data: &MarketData) {
+        if !self.internal_is_locked(market_data) {
+            return;
+        }
+        ext_contract::ext(market_data.nft_contract_id.clone())
+            .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+            .nft_is_approved(
+                market_data.token_id.clone(),
+                env::current_account_id(),
+                Some(market_data.approval_id),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_RESTORE_LISTING)
+                    .resolve_restore_listing(
+                        market_data.nft_contract_id.clone(),
+                        market_data.token_id.clone(),
+                        market_data.approval_id.into(),
+                    ),
+            );
+    }
+
+    /// the listing may have been replaced while its purchase was in flight
+    pub(crate) fn internal_is_locked(&self, market_data: &MarketData) -> bool {
+        self.market
+            .get(&format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id))
+            .is_some_and(|stored| stored.status == ListingStatus::Locked && stored.approval_id == market_data.approval_id)
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[private]
+    pub fn resolve_restore_listing(&mut self, nft_contract_id: AccountId, token_id: TokenId, approval_id: U64) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut market_data = match self.market.get(&contract_and_token_id) {
+            Some(market_data)
+                if market_data.status == ListingStatus::Locked && market_data.approval_id == approval_id.0 =>
+            {
+                market_data
+            }
+            _ => return,
+        };
+
+        // a contract that can't tell keeps the listing, it can still be pruned later
+        if approval_check_result() == Some(false) {
+            self.internal_invalidate_listing(&market_data, "not_approved");
+            return;
+        }
+
+        self.internal_unlock_listing(&mut market_data);
+    }
+
+    /// anyone can release a listing locked for LISTING_LOCK_TIMEOUT, its purchase callback ran out of gas
+    /// or panicked and will never resolve it. The buyer's escrow goes to pending withdrawals and the listing
+    /// goes back on sale, then it is pruned if the marketplace's approval is gone
+    pub fn release_locked_listing(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        let mut market_data = self
+            .market
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .expect("DS: Market data does not exist");
+        assert_eq!(market_data.status, ListingStatus::Locked, "DS: Sale is not locked");
+        let lock = market_data.lock.clone().expect("DS: Sale is not locked");
+        assert!(
+            env::block_timestamp() >= lock.locked_at.saturating_add(LISTING_LOCK_TIMEOUT),
+            "DS: Sale is locked until {}",
+            lock.locked_at.saturating_add(LISTING_LOCK_TIMEOUT)
+        );
+        self.internal_credit_pending(market_data.ft_token_id.clone(), lock.buyer_id.clone(), lock.escrow);
+        env::log_str(
+            &json!({
+                "type": "release_locked_listing",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "buyer_id": lock.buyer_id,
+                    "escrow": U128(lock.escrow),
+                }
+            })
+            .to_string(),
+        );
+        self.internal_unlock_listing(&mut market_data);
+        self.prune_invalid_listing(nft_contract_id, token_id)
+    }
+}
+
+impl Marketplace {
+    /// unlocked, the listing goes back to whatever its times say
+    fn internal_unlock_listing(&mut self, market_data: &mut MarketData) {
+        market_data.status = ListingStatus::Active;
+        market_data.lock = None;
+        let status = market_data.status_at(env::block_timestamp());
+        market_data.status = status;
+        self.market.insert(
+            &format!("{}{}{}", market_data.nft_contract_id, DELIMETER, market_data.token_id),
+            market_data,
+        );
+        log_listing_status(market_data, status);
+    }
+}
+
+pub(crate) fn log_listing_status(market_data: &MarketData, status: ListingStatus) {
+    env::log_str(
+        &json!({
+            "type": "update_listing_status",
+            "params": {
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+                "status": status,
+            }
+        })
+        .to_string(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000_000;
+
+    fn locked_listing(contract: &mut Marketplace) -> MarketData {
+        list(contract, "seller.near", "1", fixed_price(PRICE));
+        set_context("buyer.near", PRICE, 0);
+        contract.internal_lock_listing(&account(NFT), &"1".to_string(), &account("buyer.near"), PRICE)
+    }
+
+    fn approval_result(approved: bool) -> Vec<PromiseResult> {
+        vec![PromiseResult::Successful(approved.to_string().into_bytes())]
+    }
+
+    #[test]
+    fn locking_takes_the_listing_out_of_trading() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+        assert_eq!(market_data.lock.unwrap().buyer_id, account("buyer.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Sale is being purchased")]
+    fn a_locked_listing_cannot_be_locked_again() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        contract.internal_lock_listing(&account(NFT), &"1".to_string(), &account("other.near"), PRICE);
+    }
+
+    #[test]
+    fn a_failed_transfer_restores_the_approved_listing() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        set_context_with_results("market.near", 0, 0, approval_result(true));
+        contract.resolve_restore_listing(account(NFT), "1".to_string(), U64(0));
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Active);
+        assert!(market_data.lock.is_none());
+    }
+
+    #[test]
+    fn a_failed_transfer_removes_the_unapproved_listing() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        set_context_with_results("market.near", 0, 0, approval_result(false));
+        contract.resolve_restore_listing(account(NFT), "1".to_string(), U64(0));
+        assert!(market_data_of(&contract, "1").is_none());
+    }
+
+    #[test]
+    fn a_transfer_that_went_through_settles_the_listing() {
+        let mut contract = new_marketplace();
+        let market_data = locked_listing(&mut contract);
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![PromiseResult::Successful(br#"{"payout": {"seller.near": "1000000"}}"#.to_vec())],
+        );
+        contract.resolve_purchase(account("buyer.near"), market_data, U128(PRICE), None);
+        assert!(market_data_of(&contract, "1").is_none());
+        assert_eq!(pending(&contract, "seller.near"), PRICE - PRICE * 250 / 10_000);
+        assert_eq!(pending(&contract, "buyer.near"), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Sale is locked until")]
+    fn a_lock_cannot_be_released_before_it_times_out() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        set_context("anyone.near", 0, LISTING_LOCK_TIMEOUT - 1);
+        contract.release_locked_listing(account(NFT), "1".to_string());
+    }
+
+    #[test]
+    fn anyone_releases_a_timed_out_lock_and_the_buyer_is_refunded() {
+        let mut contract = new_marketplace();
+        locked_listing(&mut contract);
+        set_context("anyone.near", 0, LISTING_LOCK_TIMEOUT);
+        contract.release_locked_listing(account(NFT), "1".to_string());
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Active);
+        assert!(market_data.lock.is_none());
+    }
+}
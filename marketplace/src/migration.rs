@@ -0,0 +1,182 @@
+use crate::*;
+
+// where migrate parks the listings of the first release until migrate_listings has refunded them
+const LEGACY_MARKET_KEY: &[u8] = b"legacy_market";
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyBid {
+    pub bidder_id: AccountId,
+    pub price: U128,
+    pub time: u64,
+}
+
+/// a listing as stored by the first release, its bids were held inline
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyMarketData {
+    pub owner_id: AccountId,
+    pub approval_id: u64,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub price: u128,
+    pub bids: Option<Vec<LegacyBid>>,
+    pub started_at: Option<u64>,
+    pub ended_at: Option<u64>,
+    pub end_price: Option<u128>,
+    pub is_auction: Option<bool>,
+}
+
+/// contract state of the first release
+#[derive(BorshDeserialize, BorshSerialize)]
+struct LegacyMarketplace {
+    owner_id: AccountId,
+    treasury_id: AccountId,
+    approved_nft_contract_ids: UnorderedSet<AccountId>,
+    storage_deposits: LookupMap<AccountId, u128>,
+    transaction_fee: u16,
+    by_owner_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    market: UnorderedMap<ContractAndTokenId, LegacyMarketData>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// upgrades the state of the first release. Configuration, approved NFT contracts and storage deposits
+    /// carry over, its listings can't be carried over and are cancelled by migrate_listings
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let legacy: LegacyMarketplace = env::state_read().expect("DS: No state to migrate");
+        env::storage_write(LEGACY_MARKET_KEY, &borsh::to_vec(&legacy.market).unwrap());
+
+        let mut this = Self::new(legacy.owner_id, legacy.treasury_id, None, None, legacy.transaction_fee);
+        this.approved_nft_contract_ids = legacy.approved_nft_contract_ids;
+        this.storage_deposits = legacy.storage_deposits;
+        this.by_owner_id = legacy.by_owner_id;
+        this
+    }
+
+    /// cancels up to `limit` listings left from the first release, their bids go to pending withdrawals.
+    /// Sellers list again with nft_approve. Returns how many are left
+    #[payable]
+    pub fn migrate_listings(&mut self, limit: Option<u64>) -> U64 {
+        assert_one_yocto();
+        self.assert_owner();
+        let mut legacy_market: UnorderedMap<ContractAndTokenId, LegacyMarketData> =
+            match env::storage_read(LEGACY_MARKET_KEY) {
+                Some(value) => borsh::from_slice(&value).unwrap(),
+                None => return U64(0),
+            };
+        let contract_and_token_ids: Vec<ContractAndTokenId> =
+            legacy_market.keys().take(limit.unwrap_or(50) as usize).collect();
+
+        let mut cancelled = Vec::with_capacity(contract_and_token_ids.len());
+        for contract_and_token_id in contract_and_token_ids {
+            let market_data = legacy_market.remove(&contract_and_token_id).unwrap();
+            for bid in market_data.bids.iter().flatten() {
+                self.internal_credit_pending(None, bid.bidder_id.clone(), bid.price.0);
+            }
+            if let Some(mut by_owner_id) = self.by_owner_id.get(&market_data.owner_id) {
+                by_owner_id.remove(&contract_and_token_id);
+                if by_owner_id.is_empty() {
+                    self.by_owner_id.remove(&market_data.owner_id);
+                } else {
+                    self.by_owner_id.insert(&market_data.owner_id, &by_owner_id);
+                }
+            }
+            cancelled.push(json!({
+                "owner_id": market_data.owner_id,
+                "nft_contract_id": market_data.nft_contract_id,
+                "token_id": market_data.token_id,
+            }));
+        }
+
+        let remaining = legacy_market.len();
+        if remaining == 0 {
+            env::storage_remove(LEGACY_MARKET_KEY);
+        } else {
+            env::storage_write(LEGACY_MARKET_KEY, &borsh::to_vec(&legacy_market).unwrap());
+        }
+
+        env::log_str(
+            &json!({
+                "type": "migrate_listings",
+                "params": {
+                    "listings": cancelled,
+                    "remaining": U64(remaining),
+                }
+            })
+            .to_string(),
+        );
+        U64(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn account(name: &str) -> AccountId {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn migrate_keeps_the_configuration_and_credits_legacy_bidders() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("market.near"))
+            .predecessor_account_id(account("owner.near"))
+            .attached_deposit(ONE_YOCTONEAR)
+            .build());
+
+        let mut legacy = LegacyMarketplace {
+            owner_id: account("owner.near"),
+            treasury_id: account("treasury.near"),
+            approved_nft_contract_ids: UnorderedSet::new(StorageKey::NFTContractIds),
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+            transaction_fee: 250,
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            market: UnorderedMap::new(StorageKey::Market),
+        };
+        legacy.approved_nft_contract_ids.insert(&account("nft.near"));
+        legacy.storage_deposits.insert(&account("seller.near"), &STORAGE_ADD_MARKET_DATA);
+        let contract_and_token_id = format!("nft.near{}1", DELIMETER);
+        let mut token_ids = UnorderedSet::new(StorageKey::ByOwnerIdInner {
+            account_id_hash: hash_account_id(&account("seller.near")),
+        });
+        token_ids.insert(&contract_and_token_id);
+        legacy.by_owner_id.insert(&account("seller.near"), &token_ids);
+        legacy.market.insert(
+            &contract_and_token_id,
+            &LegacyMarketData {
+                owner_id: account("seller.near"),
+                approval_id: 0,
+                nft_contract_id: account("nft.near"),
+                token_id: "1".to_string(),
+                price: 100,
+                bids: Some(vec![LegacyBid {
+                    bidder_id: account("bidder.near"),
+                    price: U128(150),
+                    time: 0,
+                }]),
+                started_at: None,
+                ended_at: None,
+                end_price: None,
+                is_auction: Some(true),
+            },
+        );
+        env::state_write(&legacy);
+
+        let mut contract = Marketplace::migrate();
+        let config = contract.get_config();
+        assert_eq!(config.owner_id, account("owner.near"));
+        assert_eq!(config.transaction_fee, 250);
+        assert_eq!(contract.approved_nft_contract_ids(), vec![account("nft.near")]);
+        assert_eq!(contract.storage_deposits.get(&account("seller.near")), Some(STORAGE_ADD_MARKET_DATA));
+        assert_eq!(contract.get_supply_by_owner_id(account("seller.near")), U64(1));
+
+        assert_eq!(contract.migrate_listings(None), U64(0));
+        assert_eq!(contract.get_pending_withdrawal(account("bidder.near"), None), U128(150));
+        assert_eq!(contract.get_supply_by_owner_id(account("seller.near")), U64(0));
+        assert_eq!(contract.migrate_listings(None), U64(0));
+    }
+}
@@ -1,8 +1,10 @@
 use crate::*;
 /// approval callbacks from NFT Contracts
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 #[serde(crate = "near_sdk::serde")]
 pub struct MarketArgs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub market_type: Option<String>, // "sale" (default), "accept_offer", "fill_collection_offer", "bundle", "swap" or "accept_swap"
     pub price: Option<U128>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub started_at: Option<U64>,
@@ -12,9 +14,31 @@ pub struct MarketArgs {
     pub is_auction: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub end_price: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ft_token_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buyer_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_curve: Option<PriceCurve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_rules: Option<AuctionRules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserve_price: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy_now_price: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sealed_bid_rules: Option<SealedBidRules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_id: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_for: Option<ReservedFor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_until: Option<U64>, // the sale turns public at this time, private until it ends when None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub holder_gate: Option<HolderGate>,
 }
 
-trait NonFungibleTokenApprovalsReceiver {
+pub(crate) trait NonFungibleTokenApprovalsReceiver {
     fn nft_on_approve(
         &mut self,
         token_id: TokenId,
@@ -49,21 +73,78 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
             "DS: nft_contract_id is not approved"
         );
 
-        let MarketArgs {
-            price,
-            started_at,
-            ended_at,
-            is_auction,
-            end_price,
-        } = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
+        let args: MarketArgs = near_sdk::serde_json::from_str(&msg).expect("Not valid MarketArgs");
 
-        assert!(price.is_some(), "DS: price not specified");
+        match args.market_type.as_deref().unwrap_or("sale") {
+            "sale" => {}
+            "accept_offer" => {
+                let buyer_id = args.buyer_id.expect("DS: buyer_id not specified");
+                self.internal_accept_offer(nft_contract_id, token_id, owner_id, approval_id, buyer_id);
+                return;
+            }
+            "fill_collection_offer" => {
+                // price, when given, is the least the owner accepts for the token
+                let collection_offer = self
+                    .internal_find_collection_offer(
+                        &nft_contract_id,
+                        &args.ft_token_id,
+                        Some(&owner_id),
+                        args.price.map_or(0, |price| price.0),
+                    )
+                    .expect("DS: No collection offer to fill");
+                self.internal_fill_collection_offer(collection_offer, token_id, owner_id, approval_id);
+                return;
+            }
+            "bundle" => {
+                let bundle_id = args.bundle_id.expect("DS: bundle_id not specified");
+                self.internal_approve_bundle_item(bundle_id, nft_contract_id, token_id, owner_id, approval_id);
+                return;
+            }
+            "swap" => {
+                // swaps take their own args from msg
+                let swap_args: SwapArgs = near_sdk::serde_json::from_str(&msg).expect("Not valid SwapArgs");
+                self.internal_propose_swap(nft_contract_id, token_id, owner_id, approval_id, swap_args);
+                return;
+            }
+            "accept_swap" => {
+                let AcceptSwapArgs { swap_id } =
+                    near_sdk::serde_json::from_str(&msg).expect("Not valid AcceptSwapArgs");
+                self.internal_accept_swap(nft_contract_id, token_id, owner_id, approval_id, swap_id);
+                return;
+            }
+            _ => env::panic_str("DS: Invalid market_type"),
+        }
+
+        let price = args.price.expect("DS: price not specified");
+        if let Some(ft_token_id) = &args.ft_token_id {
+            assert!(
+                self.approved_ft_token_ids.contains(ft_token_id),
+                "DS: ft_token_id is not approved"
+            );
+        }
+
+        // a fixed price sale at or below the best standing collection offer fills it at the offer price,
+        // private and gated sales are kept for the buyers they admit
+        if !args.is_auction.unwrap_or(false)
+            && args.end_price.is_none()
+            && args.reserved_for.is_none()
+            && args.holder_gate.is_none()
+        {
+            if let Some(collection_offer) = self.internal_find_collection_offer(
+                &nft_contract_id,
+                &args.ft_token_id,
+                Some(&owner_id),
+                price.0,
+            ) {
+                self.internal_fill_collection_offer(collection_offer, token_id, owner_id, approval_id);
+                return;
+            }
+        }
 
         let storage_amount = self.storage_minimum_balance().0;
         let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
         let signer_storage_required =
-            (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-
+            (self.internal_storage_count(&signer_id) + 1) as u128 * storage_amount;
         if owner_paid_storage < signer_storage_required {
             let notif = format!(
                 "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
@@ -74,16 +155,194 @@ impl NonFungibleTokenApprovalsReceiver for Marketplace {
             env::log_str(&notif);
             return;
         }
-        self.internal_add_market_data(
-            owner_id,
-            approval_id,
+        let market_data = self.internal_add_market_data(owner_id, approval_id, nft_contract_id, token_id, args);
+        log_add_market_data(&market_data);
+    }
+}
+
+/// fixed price sales of several tokens approved together, prices[i] is the price of the i-th token
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchMarketArgs {
+    pub prices: Vec<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended_at: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ft_token_id: Option<AccountId>,
+}
+
+/// receiver of NFT contracts that approve many tokens in one call
+trait NonFungibleTokenBatchApprovalsReceiver {
+    fn nft_on_batch_approve(
+        &mut self,
+        tokens: Vec<TokenId>,
+        approval_ids: Vec<u64>,
+        owner_id: AccountId,
+        msg: String,
+    );
+}
+
+#[near_bindgen]
+impl NonFungibleTokenBatchApprovalsReceiver for Marketplace {
+    fn nft_on_batch_approve(
+        &mut self,
+        tokens: Vec<TokenId>,
+        approval_ids: Vec<u64>,
+        owner_id: AccountId,
+        msg: String,
+    ) {
+        let nft_contract_id = env::predecessor_account_id();
+        let signer_id = env::signer_account_id();
+        assert_ne!(
+            env::current_account_id(),
             nft_contract_id,
-            token_id,
-            price.unwrap(),
+            "DS: nft_on_batch_approve should only be called via cross-contract call"
+        );
+        assert_eq!(owner_id, signer_id, "DS: owner_id should be signer_id");
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "DS: nft_contract_id is not approved"
+        );
+
+        let BatchMarketArgs {
+            prices,
             started_at,
             ended_at,
-            end_price,
-            is_auction,
+            ft_token_id,
+        } = near_sdk::serde_json::from_str(&msg).expect("Not valid BatchMarketArgs");
+        assert!(
+            tokens.len() == approval_ids.len() && tokens.len() == prices.len(),
+            "DS: tokens, approval_ids and prices must have the same length"
         );
+        let unique_tokens: std::collections::HashSet<&TokenId> = tokens.iter().collect();
+        assert_eq!(unique_tokens.len(), tokens.len(), "DS: Token is in the batch twice");
+        if let Some(ft_token_id) = &ft_token_id {
+            assert!(
+                self.approved_ft_token_ids.contains(ft_token_id),
+                "DS: ft_token_id is not approved"
+            );
+        }
+
+        // one storage check for the whole batch, tokens already on sale are replaced in place
+        let new_sales = tokens
+            .iter()
+            .filter(|token_id| {
+                self.market
+                    .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+                    .is_none()
+            })
+            .count() as u64;
+        let storage_amount = self.storage_minimum_balance().0;
+        let owner_paid_storage = self.storage_deposits.get(&signer_id).unwrap_or(0);
+        let signer_storage_required =
+            (self.internal_storage_count(&signer_id) + new_sales) as u128 * storage_amount;
+        if owner_paid_storage < signer_storage_required {
+            let notif = format!(
+                "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
+                owner_paid_storage,
+                signer_storage_required / storage_amount,
+                storage_amount
+            );
+            env::log_str(&notif);
+            return;
+        }
+
+        let mut listings = Vec::with_capacity(tokens.len());
+        for ((token_id, approval_id), price) in tokens.into_iter().zip(approval_ids).zip(prices) {
+            // as with single sales, a price at or below the best collection offer fills it instead
+            if let Some(collection_offer) = self.internal_find_collection_offer(
+                &nft_contract_id,
+                &ft_token_id,
+                Some(&owner_id),
+                price.0,
+            ) {
+                self.internal_fill_collection_offer(collection_offer, token_id, owner_id.clone(), approval_id);
+                continue;
+            }
+            let market_data = self.internal_add_market_data(
+                owner_id.clone(),
+                approval_id,
+                nft_contract_id.clone(),
+                token_id,
+                MarketArgs {
+                    price: Some(price),
+                    started_at,
+                    ended_at,
+                    ft_token_id: ft_token_id.clone(),
+                    ..Default::default()
+                },
+            );
+            listings.push(json!({
+                "token_id": market_data.token_id,
+                "approval_id": market_data.approval_id,
+                "price": price,
+            }));
+        }
+
+        env::log_str(
+            &json!({
+                "type": "add_market_data_batch",
+                "params": {
+                    "owner_id": owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "started_at": started_at,
+                    "ended_at": ended_at,
+                    "ft_token_id": ft_token_id,
+                    "listings": listings,
+                }
+            })
+            .to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    fn batch_approve(contract: &mut Marketplace, tokens: &[&str], prices: &[u128]) {
+        let context = VMContextBuilder::new()
+            .current_account_id(account("market.near"))
+            .predecessor_account_id(account(NFT))
+            .signer_account_id(account("seller.near"))
+            .build();
+        testing_env!(context);
+        let msg = near_sdk::serde_json::to_string(&BatchMarketArgs {
+            prices: prices.iter().map(|price| U128(*price)).collect(),
+            started_at: None,
+            ended_at: None,
+            ft_token_id: None,
+        })
+        .unwrap();
+        contract.nft_on_batch_approve(
+            tokens.iter().map(|token_id| token_id.to_string()).collect(),
+            (0..tokens.len() as u64).collect(),
+            account("seller.near"),
+            msg,
+        );
+    }
+
+    #[test]
+    fn a_batch_approval_lists_every_token() {
+        let mut contract = new_marketplace();
+        deposit_storage(&mut contract, "seller.near");
+        deposit_storage(&mut contract, "seller.near");
+        batch_approve(&mut contract, &["1", "2"], &[100, 200]);
+        assert_eq!(market_data_of(&contract, "1").unwrap().price, 100);
+        assert_eq!(market_data_of(&contract, "2").unwrap().price, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Token is in the batch twice")]
+    fn a_batch_cannot_list_a_token_twice() {
+        let mut contract = new_marketplace();
+        deposit_storage(&mut contract, "seller.near");
+        deposit_storage(&mut contract, "seller.near");
+        batch_approve(&mut contract, &["1", "1"], &[100, 200]);
     }
 }
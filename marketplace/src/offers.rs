@@ -0,0 +1,385 @@
+use crate::*;
+
+/// escrowed offer on any token of an approved nft contract, listed or not
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Offer {
+    pub buyer_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub price: U128,
+    pub ft_token_id: Option<AccountId>, // None means escrowed in NEAR
+    pub expires_at: U64,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[payable]
+    pub fn add_offer(&mut self, nft_contract_id: AccountId, token_id: TokenId, expires_at: U64) {
+        self.internal_add_offer(
+            nft_contract_id,
+            token_id,
+            env::predecessor_account_id(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+            expires_at,
+        );
+    }
+
+    #[payable]
+    pub fn delete_offer(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let buyer_id = env::predecessor_account_id();
+        let offer = self
+            .internal_delete_offer(&nft_contract_id, &token_id, &buyer_id)
+            .expect("DS: Offer does not exist");
+        assert!(
+            env::block_timestamp() > offer.expires_at.0,
+            "DS: Offer has not expired yet"
+        );
+        self.internal_credit_pending(offer.ft_token_id, buyer_id.clone(), offer.price.0);
+
+        env::log_str(
+            &json!({
+                "type": "delete_offer",
+                "params": {
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get_offer(&self, nft_contract_id: AccountId, token_id: TokenId, buyer_id: AccountId) -> Option<Offer> {
+        let contract_token_and_buyer_id = format!(
+            "{}{}{}{}{}",
+            nft_contract_id, DELIMETER, token_id, DELIMETER, buyer_id
+        );
+        self.offers.get(&contract_token_and_buyer_id)
+    }
+
+    pub fn get_offers_by_token(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Offer> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let buyer_ids = match self.offers_by_token.get(&contract_and_token_id) {
+            Some(buyer_ids) => buyer_ids,
+            None => return vec![],
+        };
+        buyer_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|buyer_id| {
+                self.offers.get(&format!("{}{}{}", contract_and_token_id, DELIMETER, buyer_id))
+            })
+            .collect()
+    }
+
+    pub fn get_offers_by_bidder_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Offer> {
+        let contract_and_token_ids = match self.offers_by_bidder_id.get(&account_id) {
+            Some(contract_and_token_ids) => contract_and_token_ids,
+            None => return vec![],
+        };
+        contract_and_token_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|contract_and_token_id| {
+                self.offers.get(&format!("{}{}{}", contract_and_token_id, DELIMETER, account_id))
+            })
+            .collect()
+    }
+
+    pub fn get_supply_offers_by_bidder_id(&self, account_id: AccountId) -> U64 {
+        self.offers_by_bidder_id
+            .get(&account_id)
+            .map_or(0, |offers| offers.len())
+            .into()
+    }
+
+    pub(crate) fn internal_add_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        price: u128,
+        expires_at: U64,
+    ) {
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "DS: nft_contract_id is not approved"
+        );
+        assert!(price > 0, "DS: Offer price must be greater than 0");
+        assert!(
+            expires_at.0 > env::block_timestamp(),
+            "DS: Offer expiry must be in the future"
+        );
+
+        // a new offer on the same token replaces the previous one
+        match self.internal_delete_offer(&nft_contract_id, &token_id, &buyer_id) {
+            Some(previous_offer) => {
+                self.internal_credit_pending(previous_offer.ft_token_id, buyer_id.clone(), previous_offer.price.0);
+            }
+            None => {
+                let storage_amount = self.storage_minimum_balance().0;
+                let buyer_paid_storage = self.storage_deposits.get(&buyer_id).unwrap_or(0);
+                let buyer_storage_required =
+                    (self.internal_storage_count(&buyer_id) + 1) as u128 * storage_amount;
+                assert!(
+                    buyer_paid_storage >= buyer_storage_required,
+                    "DS: Insufficient storage paid: {}, for {} entries at {} rate of per entry",
+                    buyer_paid_storage,
+                    buyer_storage_required / storage_amount,
+                    storage_amount
+                );
+            }
+        }
+
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let contract_token_and_buyer_id =
+            format!("{}{}{}", contract_and_token_id, DELIMETER, buyer_id);
+        self.offers.insert(
+            &contract_token_and_buyer_id,
+            &Offer {
+                buyer_id: buyer_id.clone(),
+                nft_contract_id: nft_contract_id.clone(),
+                token_id: token_id.clone(),
+                price: price.into(),
+                ft_token_id: ft_token_id.clone(),
+                expires_at,
+            },
+        );
+
+        let mut buyer_ids = self.offers_by_token.get(&contract_and_token_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::OffersByTokenInner {
+                contract_and_token_id_hash: env::sha256_array(contract_and_token_id.as_bytes()),
+            })
+        });
+        buyer_ids.insert(&buyer_id);
+        self.offers_by_token.insert(&contract_and_token_id, &buyer_ids);
+
+        let mut contract_and_token_ids = self.offers_by_bidder_id.get(&buyer_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::OffersByBidderIdInner {
+                account_id_hash: hash_account_id(&buyer_id),
+            })
+        });
+        contract_and_token_ids.insert(&contract_and_token_id);
+        self.offers_by_bidder_id.insert(&buyer_id, &contract_and_token_ids);
+
+        env::log_str(
+            &json!({
+                "type": "add_offer",
+                "params": {
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "price": U128(price),
+                    "ft_token_id": ft_token_id,
+                    "expires_at": expires_at,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// called from nft_on_approve when the token owner accepts `buyer_id`'s offer
+    pub(crate) fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        buyer_id: AccountId,
+    ) -> Promise {
+        let offer = self
+            .internal_delete_offer(&nft_contract_id, &token_id, &buyer_id)
+            .expect("DS: Offer does not exist");
+        assert!(
+            env::block_timestamp() <= offer.expires_at.0,
+            "DS: Offer has expired"
+        );
+        assert_ne!(buyer_id, owner_id, "DS: Cannot accept your own offer");
+
+        env::log_str(
+            &json!({
+                "type": "accept_offer",
+                "params": {
+                    "owner_id": owner_id,
+                    "buyer_id": buyer_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "price": offer.price,
+                }
+            })
+            .to_string(),
+        );
+
+        self.internal_sell_to_buyer(
+            nft_contract_id,
+            token_id,
+            owner_id,
+            approval_id,
+            buyer_id,
+            offer.price.0,
+            offer.ft_token_id,
+        )
+    }
+
+    /// settles an escrowed offer against a freshly approved token through the normal purchase path:
+    /// the token is listed for the buyer alone until now, then locked, approval checked and transferred.
+    /// A failed transfer refunds the buyer and leaves the ended listing for settle_expired to remove
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_sell_to_buyer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        buyer_id: AccountId,
+        price: u128,
+        ft_token_id: Option<AccountId>,
+    ) -> Promise {
+        // an existing sale of the token is replaced by the accepted offer if it could be cancelled
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        if let Some(mut market_data) = self.market.get(&contract_and_token_id) {
+            let blocker = self.internal_cancel_blocker(&mut market_data);
+            assert!(blocker.is_none(), "{}", blocker.unwrap_or_default());
+        }
+
+        let market_data = self.internal_add_market_data(
+            owner_id,
+            approval_id,
+            nft_contract_id.clone(),
+            token_id.clone(),
+            MarketArgs {
+                price: Some(U128(price)),
+                ended_at: Some(U64(env::block_timestamp())),
+                ft_token_id,
+                reserved_for: Some(ReservedFor::Account(buyer_id.clone())),
+                ..Default::default()
+            },
+        );
+        log_add_market_data(&market_data);
+        self.internal_process_purchase(nft_contract_id, token_id, buyer_id, price, None)
+    }
+
+    pub(crate) fn internal_delete_offer(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        buyer_id: &AccountId,
+    ) -> Option<Offer> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let offer = self
+            .offers
+            .remove(&format!("{}{}{}", contract_and_token_id, DELIMETER, buyer_id))?;
+
+        if let Some(mut buyer_ids) = self.offers_by_token.get(&contract_and_token_id) {
+            buyer_ids.remove(buyer_id);
+            if buyer_ids.is_empty() {
+                self.offers_by_token.remove(&contract_and_token_id);
+            } else {
+                self.offers_by_token.insert(&contract_and_token_id, &buyer_ids);
+            }
+        }
+        if let Some(mut contract_and_token_ids) = self.offers_by_bidder_id.get(buyer_id) {
+            contract_and_token_ids.remove(&contract_and_token_id);
+            if contract_and_token_ids.is_empty() {
+                self.offers_by_bidder_id.remove(buyer_id);
+            } else {
+                self.offers_by_bidder_id.insert(buyer_id, &contract_and_token_ids);
+            }
+        }
+        Some(offer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000_000;
+    const EXPIRES_AT: u64 = 1_000;
+
+    fn add_test_offer(contract: &mut Marketplace) {
+        deposit_storage(contract, "buyer.near");
+        set_context("buyer.near", PRICE, 0);
+        contract.add_offer(account(NFT), "1".to_string(), U64(EXPIRES_AT));
+    }
+
+    fn accept_msg() -> String {
+        json!({"market_type": "accept_offer", "buyer_id": "buyer.near"}).to_string()
+    }
+
+    #[test]
+    fn accepting_an_offer_locks_a_sale_for_its_buyer() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract);
+        approve(&mut contract, "seller.near", "1", 0, &accept_msg());
+
+        assert!(contract.get_offer(account(NFT), "1".to_string(), account("buyer.near")).is_none());
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+        assert_eq!(market_data.price, PRICE);
+        assert_eq!(market_data.lock.unwrap().buyer_id, account("buyer.near"));
+        assert_eq!(market_data.reservation.unwrap().account_ids, vec![account("buyer.near")]);
+    }
+
+    #[test]
+    fn a_failed_offer_sale_refunds_the_escrow() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract);
+        approve(&mut contract, "seller.near", "1", 0, &accept_msg());
+        let market_data = market_data_of(&contract, "1").unwrap();
+
+        set_context_with_results("market.near", 0, 0, vec![PromiseResult::Failed]);
+        contract.resolve_purchase(account("buyer.near"), market_data, U128(PRICE), None);
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+        assert_eq!(pending(&contract, "seller.near"), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Offer has expired")]
+    fn an_expired_offer_cannot_be_accepted() {
+        let mut contract = new_marketplace();
+        add_test_offer(&mut contract);
+        set_context("seller.near", 0, EXPIRES_AT + 1);
+        approve(&mut contract, "seller.near", "1", 0, &accept_msg());
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Auction has not ended yet")]
+    fn an_offer_cannot_replace_an_auction_with_bids() {
+        let mut contract = new_marketplace();
+        list(
+            &mut contract,
+            "seller.near",
+            "1",
+            MarketArgs {
+                price: Some(U128(PRICE)),
+                is_auction: Some(true),
+                ended_at: Some(U64(EXPIRES_AT)),
+                ..Default::default()
+            },
+        );
+        deposit_storage(&mut contract, "bidder.near");
+        set_context("bidder.near", PRICE, 0);
+        contract.add_bid(account(NFT), "1".to_string(), U128(PRICE), None);
+        add_test_offer(&mut contract);
+        approve(&mut contract, "seller.near", "1", 1, &accept_msg());
+    }
+}
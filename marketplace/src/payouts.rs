@@ -0,0 +1,202 @@
+use crate::*;
+
+/// a NEP-199 payout checked against the sale it pays for, royalties are every receiver but the seller
+#[derive(Debug, PartialEq)]
+pub struct PayoutSplit {
+    pub royalties: Vec<(AccountId, u128)>,
+    pub seller_amount: u128,
+}
+
+// every receiver of a payout may take a new pending withdrawal entry on the contract's storage
+pub const MAX_LEN_PAYOUT: u32 = 20;
+
+#[near_bindgen]
+impl Marketplace {
+    /// `max_len_payout` is passed to nft_transfer_payout, `max_royalty_bps` caps what a sale pays
+    /// in royalties in total
+    #[payable]
+    pub fn set_payout_policy(&mut self, max_len_payout: u32, max_royalty_bps: u16) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(
+            max_len_payout > 0 && max_len_payout <= MAX_LEN_PAYOUT,
+            "DS: max_len_payout must be between 1 and {}",
+            MAX_LEN_PAYOUT
+        );
+        assert!(max_royalty_bps <= 10_000, "DS: max_royalty_bps can't be above 10000");
+        self.max_len_payout = max_len_payout;
+        self.max_royalty_bps = max_royalty_bps;
+    }
+
+    pub(crate) fn internal_split_payout(&self, value: &[u8], price: u128, seller_id: &AccountId) -> Option<PayoutSplit> {
+        split_payout(value, price, seller_id, self.max_len_payout, self.max_royalty_bps)
+    }
+}
+
+/// checks what nft_transfer_payout returned for a sale of `price`. None rejects the payout: it is not
+/// a NEP-199 payout, has more than `max_len_payout` receivers or adds up to more than the price.
+/// Royalties above `max_royalty_bps` of the price in total are scaled down and whatever the payout
+/// leaves unassigned goes to the seller
+pub fn split_payout(
+    value: &[u8],
+    price: u128,
+    seller_id: &AccountId,
+    max_len_payout: u32,
+    max_royalty_bps: u16,
+) -> Option<PayoutSplit> {
+    let Payout { payout } = near_sdk::serde_json::from_slice(value).ok()?;
+    if payout.len() > max_len_payout as usize {
+        return None;
+    }
+    let mut total: u128 = 0;
+    for amount in payout.values() {
+        total = total.checked_add(amount.0)?;
+    }
+    if total > price {
+        return None;
+    }
+
+    let mut royalties: Vec<(AccountId, u128)> = payout
+        .into_iter()
+        .filter(|(receiver_id, amount)| receiver_id != seller_id && amount.0 > 0)
+        .map(|(receiver_id, amount)| (receiver_id, amount.0))
+        .collect();
+    royalties.sort();
+    let royalty_total: u128 = royalties.iter().map(|(_, amount)| amount).sum();
+    let max_royalty = mul_div(price, max_royalty_bps as u128, 10_000);
+    if royalty_total > max_royalty {
+        for (_, amount) in royalties.iter_mut() {
+            *amount = mul_div(*amount, max_royalty, royalty_total);
+        }
+    }
+    let seller_amount = price - royalties.iter().map(|(_, amount)| amount).sum::<u128>();
+    Some(PayoutSplit {
+        royalties,
+        seller_amount,
+    })
+}
+
+/// the token has moved but its payout was rejected, the buyer gets `refund` back
+pub(crate) fn log_payout_rejected(
+    owner_id: &AccountId,
+    nft_contract_id: &AccountId,
+    token_id: &TokenId,
+    buyer_id: &AccountId,
+    ft_token_id: &Option<AccountId>,
+    refund: u128,
+) {
+    env::log_str(
+        &json!({
+            "type": "payout_rejected",
+            "params": {
+                "owner_id": owner_id,
+                "nft_contract_id": nft_contract_id,
+                "token_id": token_id,
+                "buyer_id": buyer_id,
+                "ft_token_id": ft_token_id,
+                "refund": U128(refund),
+            }
+        })
+        .to_string(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000_000;
+
+    fn seller() -> AccountId {
+        "seller.near".parse().unwrap()
+    }
+
+    fn split(value: &str) -> Option<PayoutSplit> {
+        split_payout(value.as_bytes(), PRICE, &seller(), 3, 1_000)
+    }
+
+    #[test]
+    fn splits_royalties_from_the_seller_share() {
+        let split = split(r#"{"payout": {"seller.near": "950000", "artist.near": "50000"}}"#).unwrap();
+        assert_eq!(split.royalties, vec![("artist.near".parse().unwrap(), 50_000)]);
+        assert_eq!(split.seller_amount, 950_000);
+    }
+
+    #[test]
+    fn unassigned_remainder_goes_to_the_seller() {
+        let split = split(r#"{"payout": {"seller.near": "900000", "artist.near": "50000"}}"#).unwrap();
+        assert_eq!(split.seller_amount, 950_000);
+    }
+
+    #[test]
+    fn caps_royalties_proportionally() {
+        let split = split(r#"{"payout": {"seller.near": "400000", "a.near": "300000", "b.near": "300000"}}"#).unwrap();
+        assert_eq!(
+            split.royalties,
+            vec![("a.near".parse().unwrap(), 50_000), ("b.near".parse().unwrap(), 50_000)]
+        );
+        assert_eq!(split.seller_amount, 900_000);
+    }
+
+    #[test]
+    fn rejects_payouts_above_the_price() {
+        assert_eq!(split(r#"{"payout": {"seller.near": "1000000", "artist.near": "1"}}"#), None);
+    }
+
+    #[test]
+    fn rejects_overflowing_payouts() {
+        let max = u128::MAX.to_string();
+        assert_eq!(split(&format!(r#"{{"payout": {{"seller.near": "{max}", "artist.near": "{max}"}}}}"#)), None);
+    }
+
+    #[test]
+    fn rejects_payouts_longer_than_max_len_payout() {
+        assert_eq!(
+            split(r#"{"payout": {"seller.near": "1", "a.near": "1", "b.near": "1", "c.near": "1"}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_payouts() {
+        assert_eq!(split(""), None);
+        assert_eq!(split("null"), None);
+        assert_eq!(split(r#"{"seller.near": "1000000"}"#), None);
+        assert_eq!(split(r#"{"payout": {"seller.near": 1000000}}"#), None);
+        assert_eq!(split(r#"{"payout": {"seller.near": "-1"}}"#), None);
+        assert_eq!(split(r#"{"payout": {"not an account": "1"}}"#), None);
+        assert_eq!(split(r#"{"payout": ["seller.near", "1000000"]}"#), None);
+    }
+
+    #[test]
+    fn resolve_purchase_refunds_the_buyer_of_a_rejected_payout() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        let market_data =
+            contract.internal_lock_listing(&account(NFT), &"1".to_string(), &account("buyer.near"), PRICE);
+
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![PromiseResult::Successful(br#"{"payout": {"seller.near": "2000000"}}"#.to_vec())],
+        );
+        let refund = contract.resolve_purchase(account("buyer.near"), market_data, U128(PRICE), None);
+
+        assert_eq!(refund, U128(PRICE));
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+        assert_eq!(pending(&contract, "seller.near"), 0);
+        assert_eq!(pending(&contract, "treasury.near"), 0);
+        assert!(market_data_of(&contract, "1").is_none());
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("payout_rejected")));
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: max_len_payout must be between 1 and 20")]
+    fn max_len_payout_is_capped() {
+        let mut contract = new_marketplace();
+        set_context("owner.near", 1, 0);
+        contract.set_payout_policy(MAX_LEN_PAYOUT + 1, 1_000);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::*;
+
+const GAS_FOR_RESOLVE_WITHDRAW_PENDING: Gas = Gas::from_tgas(5);
+
+/// refunds and payouts owed to an account, claimed with withdraw_pending
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingWithdrawal {
+    pub ft_token_id: Option<AccountId>, // None means NEAR
+    pub amount: U128,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// sends the caller everything owed to them in `ft_token_id`, or in NEAR when None.
+    /// A failed transfer is credited back so it can be withdrawn again
+    #[payable]
+    pub fn withdraw_pending(&mut self, ft_token_id: Option<AccountId>) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut pending = self
+            .pending_withdrawals
+            .get(&account_id)
+            .expect("DS: Nothing to withdraw");
+        let amount = pending.remove(&ft_token_id).expect("DS: Nothing to withdraw");
+        if pending.is_empty() {
+            self.pending_withdrawals.remove(&account_id);
+        } else {
+            self.pending_withdrawals.insert(&account_id, &pending);
+        }
+
+        self.internal_transfer(ft_token_id.clone(), account_id.clone(), amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW_PENDING)
+                    .resolve_withdraw_pending(account_id, ft_token_id, amount.into()),
+            )
+    }
+
+    #[private]
+    pub fn resolve_withdraw_pending(
+        &mut self,
+        account_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: U128,
+    ) -> U128 {
+        if !is_promise_success() {
+            self.internal_credit_pending(ft_token_id, account_id, amount.0);
+            return U128(0);
+        }
+
+        env::log_str(
+            &json!({
+                "type": "withdraw_pending",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+        amount
+    }
+
+    pub fn get_pending_withdrawal(&self, account_id: AccountId, ft_token_id: Option<AccountId>) -> U128 {
+        self.pending_withdrawals
+            .get(&account_id)
+            .and_then(|pending| pending.get(&ft_token_id))
+            .unwrap_or(0)
+            .into()
+    }
+
+    pub fn get_pending_withdrawals(&self, account_id: AccountId) -> Vec<PendingWithdrawal> {
+        match self.pending_withdrawals.get(&account_id) {
+            Some(pending) => pending
+                .iter()
+                .map(|(ft_token_id, amount)| PendingWithdrawal {
+                    ft_token_id,
+                    amount: amount.into(),
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// owes `amount` to `account_id`, every refund and payout goes through here instead of being sent right away.
+    /// An account's first credit in a currency stores up to 640 bytes (0.0064 NEAR) paid by the contract
+    /// until withdrawn, a sale credits at most MAX_LEN_PAYOUT receivers, the treasury and a keeper
+    pub(crate) fn internal_credit_pending(
+        &mut self,
+        ft_token_id: Option<AccountId>,
+        account_id: AccountId,
+        amount: u128,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let mut pending = self.pending_withdrawals.get(&account_id).unwrap_or_else(|| {
+            UnorderedMap::new(StorageKey::PendingWithdrawalsInner {
+                account_id_hash: hash_account_id(&account_id),
+            })
+        });
+        let balance = pending.get(&ft_token_id).unwrap_or(0);
+        pending.insert(&ft_token_id, &(balance + amount));
+        self.pending_withdrawals.insert(&account_id, &pending);
+
+        env::log_str(
+            &json!({
+                "type": "credit_pending",
+                "params": {
+                    "account_id": account_id,
+                    "ft_token_id": ft_token_id,
+                    "amount": U128(amount),
+                }
+            })
+            .to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // storage of an account's first pending withdrawal in a currency, with 64 character account ids
+    const MAX_PENDING_ENTRY_BYTES: u64 = 640;
+
+    #[test]
+    fn a_first_credit_fits_max_pending_entry_bytes() {
+        let mut contract = new_marketplace();
+        let account_id = account(&format!("{}.near", "a".repeat(59)));
+        let ft_token_id = account(&format!("{}.near", "b".repeat(59)));
+        let storage_usage = env::storage_usage();
+        contract.internal_credit_pending(Some(ft_token_id.clone()), account_id.clone(), 1);
+        assert!(env::storage_usage() - storage_usage <= MAX_PENDING_ENTRY_BYTES);
+
+        // later credits add to the entry
+        let storage_usage = env::storage_usage();
+        contract.internal_credit_pending(Some(ft_token_id.clone()), account_id.clone(), 1);
+        assert_eq!(env::storage_usage(), storage_usage);
+        assert_eq!(contract.get_pending_withdrawal(account_id, Some(ft_token_id)), U128(2));
+    }
+}
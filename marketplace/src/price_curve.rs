@@ -0,0 +1,227 @@
+use crate::*;
+
+// exponential curves are evaluated period by period, bound the work a price lookup can take
+pub const MAX_PRICE_CURVE_STEPS: u64 = 1000;
+
+/// how a dutch auction moves from price to end_price between started_at and ended_at.
+/// Every curve starts at price, never goes below end_price and is exactly end_price from ended_at on
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", tag = "type", rename_all = "snake_case")]
+pub enum PriceCurve {
+    Linear,
+    /// what is left above end_price shrinks by decay_bps every period (nanoseconds)
+    Exponential { decay_bps: u16, period: U64 },
+    /// the price drops by step_amount every step_duration (nanoseconds)
+    Stepped { step_amount: U128, step_duration: U64 },
+}
+
+impl PriceCurve {
+    pub fn assert_valid(&self, started_at: u64, ended_at: u64) {
+        match self {
+            PriceCurve::Linear => {}
+            PriceCurve::Exponential { decay_bps, period } => {
+                assert!(
+                    *decay_bps > 0 && *decay_bps < 10_000,
+                    "DS: decay_bps must be between 1 and 9999"
+                );
+                assert!(period.0 > 0, "DS: Curve period must be greater than 0");
+                assert!(
+                    (ended_at - started_at) / period.0 <= MAX_PRICE_CURVE_STEPS,
+                    "DS: Curve can have at most {} periods",
+                    MAX_PRICE_CURVE_STEPS
+                );
+            }
+            PriceCurve::Stepped {
+                step_amount,
+                step_duration,
+            } => {
+                assert!(step_amount.0 > 0, "DS: step_amount must be greater than 0");
+                assert!(step_duration.0 > 0, "DS: step_duration must be greater than 0");
+            }
+        }
+    }
+
+    pub fn price_at(
+        &self,
+        price: u128,
+        end_price: u128,
+        started_at: u64,
+        ended_at: u64,
+        timestamp: u64,
+    ) -> u128 {
+        if timestamp <= started_at {
+            return price;
+        }
+        if timestamp >= ended_at {
+            return end_price;
+        }
+        let elapsed = (timestamp - started_at) as u128;
+        let duration = (ended_at - started_at) as u128;
+        let spread = price - end_price;
+
+        match self {
+            PriceCurve::Linear => price - mul_div(spread, elapsed, duration),
+            PriceCurve::Exponential { decay_bps, period } => {
+                let mut remaining = spread;
+                for _ in 0..(elapsed / period.0 as u128) {
+                    remaining = mul_div(remaining, (10_000 - decay_bps) as u128, 10_000);
+                    if remaining == 0 {
+                        break;
+                    }
+                }
+                end_price + remaining
+            }
+            PriceCurve::Stepped {
+                step_amount,
+                step_duration,
+            } => {
+                let steps = elapsed / step_duration.0 as u128;
+                let drop = step_amount.0.saturating_mul(steps);
+                end_price + spread.saturating_sub(drop)
+            }
+        }
+    }
+}
+
+/// floor(a * b / c), the product is carried in 256 bits so nothing is lost to overflow or early rounding.
+/// Callers keep b <= c, so the result always fits in u128
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    assert!(c > 0, "DS: Division by zero");
+    if let Some(product) = a.checked_mul(b) {
+        return product / c;
+    }
+
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+    let lo_lo = a_lo * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_lo = a_hi * b_lo;
+    let hi_hi = a_hi * b_hi;
+    let mid = (lo_lo >> 64) + (lo_hi & MASK) + (hi_lo & MASK);
+    let lo = (lo_lo & MASK) | (mid << 64);
+    let hi = hi_hi + (lo_hi >> 64) + (hi_lo >> 64) + (mid >> 64);
+
+    // binary long division of hi:lo by c
+    let mut quotient: u128 = 0;
+    let mut remainder: u128 = 0;
+    for i in (0..256).rev() {
+        let bit = if i >= 128 { (hi >> (i - 128)) & 1 } else { (lo >> i) & 1 };
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | bit;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            assert!(i < 128, "DS: mul_div overflow");
+            quotient |= 1 << i;
+        }
+    }
+    quotient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: u128 = 1_100;
+    const END_PRICE: u128 = 100;
+    const STARTED_AT: u64 = 1_000;
+    const ENDED_AT: u64 = 1_100;
+
+    fn price_at(curve: PriceCurve, timestamp: u64) -> u128 {
+        curve.price_at(PRICE, END_PRICE, STARTED_AT, ENDED_AT, timestamp)
+    }
+
+    fn exponential() -> PriceCurve {
+        PriceCurve::Exponential {
+            decay_bps: 5_000,
+            period: U64(10),
+        }
+    }
+
+    fn stepped(step_amount: u128) -> PriceCurve {
+        PriceCurve::Stepped {
+            step_amount: U128(step_amount),
+            step_duration: U64(10),
+        }
+    }
+
+    #[test]
+    fn every_curve_starts_at_price_and_ends_at_end_price() {
+        for curve in [PriceCurve::Linear, exponential(), stepped(100)] {
+            assert_eq!(price_at(curve.clone(), STARTED_AT - 1), PRICE);
+            assert_eq!(price_at(curve.clone(), STARTED_AT), PRICE);
+            assert_eq!(price_at(curve.clone(), ENDED_AT), END_PRICE);
+            assert_eq!(price_at(curve, ENDED_AT + 1), END_PRICE);
+        }
+    }
+
+    #[test]
+    fn linear_moves_in_proportion_to_elapsed_time() {
+        assert_eq!(price_at(PriceCurve::Linear, STARTED_AT + 25), 850);
+        assert_eq!(price_at(PriceCurve::Linear, STARTED_AT + 50), 600);
+        // rounds in the seller's favour
+        assert_eq!(price_at(PriceCurve::Linear, STARTED_AT + 33), 770);
+    }
+
+    #[test]
+    fn linear_is_exact_for_large_prices() {
+        let price = u128::MAX;
+        let curve_price = PriceCurve::Linear.price_at(price, 0, 0, 3, 1);
+        assert_eq!(curve_price, price - price / 3);
+    }
+
+    #[test]
+    fn exponential_halves_what_is_left_every_period() {
+        assert_eq!(price_at(exponential(), STARTED_AT + 9), PRICE);
+        assert_eq!(price_at(exponential(), STARTED_AT + 10), 600);
+        assert_eq!(price_at(exponential(), STARTED_AT + 25), 350);
+        assert_eq!(price_at(exponential(), ENDED_AT - 1), END_PRICE + 1);
+    }
+
+    #[test]
+    fn stepped_drops_once_per_step() {
+        assert_eq!(price_at(stepped(100), STARTED_AT + 9), PRICE);
+        assert_eq!(price_at(stepped(100), STARTED_AT + 35), 800);
+    }
+
+    #[test]
+    fn stepped_never_goes_below_end_price() {
+        assert_eq!(price_at(stepped(400), STARTED_AT + 30), END_PRICE);
+        assert_eq!(price_at(stepped(u128::MAX), STARTED_AT + 90), END_PRICE);
+    }
+
+    #[test]
+    fn mul_div_matches_the_exact_product() {
+        assert_eq!(mul_div(7, 3, 2), 10);
+        assert_eq!(mul_div(u128::MAX, 3, 4), 255211775190703847597530955573826158591);
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(
+            mul_div(u128::MAX - 12345, (1 << 100) + 7, (1 << 101) + 3),
+            170141183460469231731687303716622297058
+        );
+        assert_eq!(
+            mul_div((1 << 127) + 99, (1 << 127) - 1, u128::MAX - 4),
+            85070591730234615865843651857942052914
+        );
+        assert_eq!(
+            mul_div(
+                123456789012345678901234567890123456,
+                987654321098765432109876543210,
+                999999999999999999999999999999999999
+            ),
+            121932631137021795226185032733
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: mul_div overflow")]
+    fn mul_div_rejects_results_above_u128() {
+        mul_div(1 << 127, 1 << 127, 1 << 126);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Division by zero")]
+    fn mul_div_rejects_division_by_zero() {
+        mul_div(1, 1, 0);
+    }
+}
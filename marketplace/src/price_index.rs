@@ -0,0 +1,184 @@
+use crate::*;
+
+/// fixed price sales of a collection sorted by price, auctions and dutch auctions are left out
+/// because their price is not known until they settle
+pub type PriceAndTokenId = (u128, TokenId);
+
+// index entries a price view reads before giving up, listings it skips count too
+pub const MAX_PRICE_INDEX_SCAN: usize = 500;
+
+impl MarketData {
+    /// the listings counted in the floor and price views: active now, and neither reserved
+    /// for other buyers nor gated. Scheduled, locked and ended sales stay indexed but are skipped
+    pub fn is_open_to_anyone_at(&self, timestamp: u64) -> bool {
+        self.status_at(timestamp) == ListingStatus::Active
+            && self
+                .reservation
+                .as_ref()
+                .is_none_or(|reservation| !reservation.is_active_at(timestamp))
+            && self.holder_gate.is_none()
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// the lowest price anyone can buy the collection at right now, see is_open_to_anyone_at.
+    /// None when no such sale is among the first MAX_PRICE_INDEX_SCAN of the index
+    pub fn get_floor_price(&self, nft_contract_id: AccountId, ft_token_id: Option<AccountId>) -> Option<U128> {
+        let by_price = self
+            .by_price
+            .get(&collection_and_currency_id(&nft_contract_id, &ft_token_id))?;
+        let timestamp = env::block_timestamp();
+        let floor = by_price
+            .iter()
+            .take(MAX_PRICE_INDEX_SCAN)
+            .find(|((_, token_id), _)| {
+                self.market
+                    .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+                    .is_some_and(|market_data| market_data.is_open_to_anyone_at(timestamp))
+            });
+        floor.map(|((price, _), _)| U128(price))
+    }
+
+    /// `from_price` is inclusive, the lowest price when ascending and the highest otherwise.
+    /// Only sales counted in the floor are returned, out of at most MAX_PRICE_INDEX_SCAN index entries
+    pub fn get_listings_by_price(
+        &self,
+        nft_contract_id: AccountId,
+        from_price: Option<U128>,
+        limit: Option<u64>,
+        ascending: Option<bool>,
+        ft_token_id: Option<AccountId>,
+    ) -> Vec<MarketDataJson> {
+        let by_price = match self
+            .by_price
+            .get(&collection_and_currency_id(&nft_contract_id, &ft_token_id))
+        {
+            Some(by_price) => by_price,
+            None => return vec![],
+        };
+        // token ids are never empty, so (price, "") sorts before every sale at that price
+        let keys: Vec<PriceAndTokenId> = if ascending.unwrap_or(true) {
+            let from_price = from_price.map_or(0, |x| x.0);
+            by_price
+                .iter_from((from_price, String::new()))
+                .take(MAX_PRICE_INDEX_SCAN)
+                .map(|(key, _)| key)
+                .collect()
+        } else {
+            match from_price.and_then(|x| x.0.checked_add(1)) {
+                Some(to_price) => by_price
+                    .iter_rev_from((to_price, String::new()))
+                    .take(MAX_PRICE_INDEX_SCAN)
+                    .map(|(key, _)| key)
+                    .collect(),
+                None => by_price
+                    .iter_rev()
+                    .take(MAX_PRICE_INDEX_SCAN)
+                    .map(|(key, _)| key)
+                    .collect(),
+            }
+        };
+        let timestamp = env::block_timestamp();
+        keys.into_iter()
+            .filter_map(|(_, token_id)| {
+                self.market.get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            })
+            .filter(|market_data| market_data.is_open_to_anyone_at(timestamp))
+            .take(limit.unwrap_or(50) as usize)
+            .map(|market_data| self.internal_market_data_json(market_data))
+            .collect()
+    }
+
+    pub(crate) fn internal_add_to_price_index(&mut self, market_data: &MarketData) {
+        if !market_data.is_fixed_price() {
+            return;
+        }
+        let collection_and_currency_id =
+            collection_and_currency_id(&market_data.nft_contract_id, &market_data.ft_token_id);
+        let mut by_price = self.by_price.get(&collection_and_currency_id).unwrap_or_else(|| {
+            TreeMap::new(StorageKey::ByPriceInner {
+                collection_and_currency_id_hash: env::sha256_array(collection_and_currency_id.as_bytes()),
+            })
+        });
+        by_price.insert(&(market_data.price, market_data.token_id.clone()), &());
+        self.by_price.insert(&collection_and_currency_id, &by_price);
+    }
+
+    pub(crate) fn internal_remove_from_price_index(&mut self, market_data: &MarketData) {
+        let collection_and_currency_id =
+            collection_and_currency_id(&market_data.nft_contract_id, &market_data.ft_token_id);
+        if let Some(mut by_price) = self.by_price.get(&collection_and_currency_id) {
+            if by_price
+                .remove(&(market_data.price, market_data.token_id.clone()))
+                .is_none()
+            {
+                return;
+            }
+            if by_price.is_empty() {
+                self.by_price.remove(&collection_and_currency_id);
+            } else {
+                self.by_price.insert(&collection_and_currency_id, &by_price);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn listings_by_price(contract: &Marketplace) -> Vec<TokenId> {
+        contract
+            .get_listings_by_price(account(NFT), None, None, None, None)
+            .into_iter()
+            .map(|market_data| market_data.token_id)
+            .collect()
+    }
+
+    #[test]
+    fn the_floor_skips_sales_not_open_to_anyone() {
+        let mut contract = new_marketplace();
+        let reserved = MarketArgs {
+            reserved_for: Some(ReservedFor::Account(account("friend.near"))),
+            ..fixed_price(100)
+        };
+        list(&mut contract, "seller.near", "reserved", reserved);
+        let scheduled = MarketArgs {
+            started_at: Some(U64(1_000)),
+            ..fixed_price(200)
+        };
+        list(&mut contract, "seller.near", "scheduled", scheduled);
+        let ending = MarketArgs {
+            ended_at: Some(U64(10)),
+            ..fixed_price(300)
+        };
+        list(&mut contract, "seller.near", "ended", ending);
+        list(&mut contract, "seller.near", "locked", fixed_price(400));
+        contract.internal_lock_listing(&account(NFT), &"locked".to_string(), &account("buyer.near"), 400);
+        list(&mut contract, "seller.near", "open", fixed_price(500));
+
+        set_context("viewer.near", 0, 20);
+        assert_eq!(contract.get_floor_price(account(NFT), None), Some(U128(500)));
+        assert_eq!(listings_by_price(&contract), vec!["open".to_string()]);
+
+        set_context("viewer.near", 0, 1_000);
+        assert_eq!(contract.get_floor_price(account(NFT), None), Some(U128(200)));
+        assert_eq!(listings_by_price(&contract), vec!["scheduled".to_string(), "open".to_string()]);
+    }
+
+    #[test]
+    fn an_expired_reservation_counts_toward_the_floor() {
+        let mut contract = new_marketplace();
+        let reserved = MarketArgs {
+            reserved_for: Some(ReservedFor::Account(account("friend.near"))),
+            reserved_until: Some(U64(10)),
+            ..fixed_price(100)
+        };
+        list(&mut contract, "seller.near", "1", reserved);
+        assert_eq!(contract.get_floor_price(account(NFT), None), None);
+        set_context("viewer.near", 0, 10);
+        assert_eq!(contract.get_floor_price(account(NFT), None), Some(U128(100)));
+    }
+}
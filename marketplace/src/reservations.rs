@@ -0,0 +1,58 @@
+use crate::*;
+
+// checked on every purchase and bid, keep the allowlist short
+pub const MAX_RESERVED_FOR: usize = 20;
+
+/// who a private sale is reserved for, given in MarketArgs as one account or a list of them
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", untagged)]
+pub enum ReservedFor {
+    Account(AccountId),
+    Allowlist(Vec<AccountId>),
+}
+
+/// only account_ids can buy or bid until expires_at, after which the sale is public
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reservation {
+    pub account_ids: Vec<AccountId>,
+    pub expires_at: Option<U64>, // None keeps the sale private until it ends
+}
+
+impl Reservation {
+    pub fn new(reserved_for: ReservedFor, expires_at: Option<U64>) -> Self {
+        let account_ids = match reserved_for {
+            ReservedFor::Account(account_id) => vec![account_id],
+            ReservedFor::Allowlist(account_ids) => account_ids,
+        };
+        assert!(
+            !account_ids.is_empty() && account_ids.len() <= MAX_RESERVED_FOR,
+            "DS: reserved_for holds 1 to {} accounts",
+            MAX_RESERVED_FOR
+        );
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at.0 > env::block_timestamp(),
+                "DS: reserved_until must be in the future"
+            );
+        }
+        Self { account_ids, expires_at }
+    }
+
+    pub fn is_active_at(&self, timestamp: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| timestamp < expires_at.0)
+    }
+}
+
+impl MarketData {
+    /// private sales only take buyers and bidders they are reserved for, until the reservation expires
+    pub fn is_reserved_for(&self, account_id: &AccountId) -> bool {
+        self.reservation.as_ref().is_none_or(|reservation| {
+            !reservation.is_active_at(env::block_timestamp()) || reservation.account_ids.contains(account_id)
+        })
+    }
+
+    pub fn assert_reserved_for(&self, account_id: &AccountId) {
+        assert!(self.is_reserved_for(account_id), "DS: Sale is reserved for another buyer");
+    }
+}
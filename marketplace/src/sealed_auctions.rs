@@ -0,0 +1,444 @@
+use crate::*;
+
+// every deposit is refunded at settlement, bound how many a single listing holds
+pub const MAX_SEALED_BIDS: u64 = 50;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum SealedBidPricing {
+    FirstPrice,  // the winner pays their own bid
+    SecondPrice, // the winner pays the runner up's bid, or the starting price without one
+}
+
+/// bids are committed from started_at until reveal_at, then revealed until ended_at
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedBidRules {
+    pub reveal_at: U64,
+    pub pricing: SealedBidPricing,
+}
+
+impl SealedBidRules {
+    pub fn assert_valid(&self, started_at: u64, ended_at: u64) {
+        assert!(
+            started_at < self.reveal_at.0 && self.reveal_at.0 < ended_at,
+            "DS: reveal_at must be between started_at and ended_at"
+        );
+    }
+}
+
+/// commitment is sha256 of "{bidder_id}||{amount}||{salt}", deposit may exceed the amount to hide it
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedBid {
+    pub bidder_id: AccountId,
+    pub commitment: Base58CryptoHash,
+    pub deposit: U128,
+    pub revealed_amount: Option<U128>,
+    pub revealed_at: Option<U64>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    #[payable]
+    pub fn commit_sealed_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, commitment: Base58CryptoHash) {
+        self.internal_commit_sealed_bid(
+            nft_contract_id,
+            token_id,
+            env::predecessor_account_id(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+            commitment,
+        );
+    }
+
+    pub fn reveal_sealed_bid(&mut self, nft_contract_id: AccountId, token_id: TokenId, amount: U128, salt: String) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        let sealed_bid_rules = market_data
+            .sealed_bid_rules
+            .expect("DS: Sale is not a sealed auction");
+        let current_time = env::block_timestamp();
+        assert!(
+            current_time >= sealed_bid_rules.reveal_at.0,
+            "DS: Reveal phase has not started yet"
+        );
+        assert!(
+            current_time <= market_data.ended_at.unwrap(),
+            "DS: Reveal phase has ended"
+        );
+
+        let bidder_id = env::predecessor_account_id();
+        let contract_token_and_bidder_id = format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id);
+        let mut sealed_bid = self
+            .sealed_bids
+            .get(&contract_token_and_bidder_id)
+            .expect("DS: Sealed bid does not exist");
+        assert!(sealed_bid.revealed_amount.is_none(), "DS: Sealed bid already revealed");
+        let preimage = format!("{}{}{}{}{}", bidder_id, DELIMETER, amount.0, DELIMETER, salt);
+        assert!(
+            CryptoHash::from(sealed_bid.commitment) == env::sha256_array(preimage.as_bytes()),
+            "DS: Amount and salt do not match the commitment"
+        );
+        assert!(
+            amount.0 >= market_data.price,
+            "DS: Can't pay less than starting price: {:?}",
+            market_data.price
+        );
+        assert!(amount.0 <= sealed_bid.deposit.0, "DS: Amount is more than the deposit");
+
+        sealed_bid.revealed_amount = Some(amount);
+        sealed_bid.revealed_at = Some(U64(current_time));
+        self.sealed_bids.insert(&contract_token_and_bidder_id, &sealed_bid);
+
+        env::log_str(
+            &json!({
+                "type": "reveal_sealed_bid",
+                "params": {
+                    "bidder_id": bidder_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "amount": amount,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// anyone can settle once the reveal phase is over, unrevealed and losing deposits are refunded
+    #[payable]
+    pub fn settle_sealed_auction(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        self.internal_settle_sealed_auction(nft_contract_id, token_id, Some(env::predecessor_account_id()));
+    }
+
+    /// `keeper_id` is rewarded from the marketplace fee when the auction ends in a sale
+    pub(crate) fn internal_settle_sealed_auction(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        keeper_id: Option<AccountId>,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        assert_ne!(market_data.status, ListingStatus::Locked, "DS: Sale is being purchased");
+        let sealed_bid_rules = market_data
+            .sealed_bid_rules
+            .clone()
+            .expect("DS: Sale is not a sealed auction");
+        assert!(
+            env::block_timestamp() > market_data.ended_at.unwrap(),
+            "DS: Auction has not ended yet"
+        );
+
+        let sealed_bids = self.internal_remove_sealed_bids(&contract_and_token_id);
+        // highest amount wins, the earlier reveal breaks ties
+        let mut revealed: Vec<(u128, u64, AccountId)> = sealed_bids
+            .iter()
+            .filter_map(|sealed_bid| {
+                sealed_bid
+                    .revealed_amount
+                    .map(|amount| (amount.0, sealed_bid.revealed_at.unwrap().0, sealed_bid.bidder_id.clone()))
+            })
+            .collect();
+        revealed.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let winner = revealed.first().map(|(amount, _, bidder_id)| {
+            let price = match sealed_bid_rules.pricing {
+                SealedBidPricing::FirstPrice => *amount,
+                SealedBidPricing::SecondPrice => revealed
+                    .get(1)
+                    .map_or(market_data.price, |(second_amount, _, _)| *second_amount),
+            };
+            (bidder_id.clone(), price)
+        });
+
+        for sealed_bid in &sealed_bids {
+            let refund = match &winner {
+                Some((winner_id, price)) if *winner_id == sealed_bid.bidder_id => sealed_bid.deposit.0 - price,
+                _ => sealed_bid.deposit.0,
+            };
+            if refund > 0 {
+                self.internal_credit_pending(market_data.ft_token_id.clone(), sealed_bid.bidder_id.clone(), refund);
+            }
+        }
+
+        env::log_str(
+            &json!({
+                "type": "settle_sealed_auction",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "winner_id": winner.as_ref().map(|(winner_id, _)| winner_id),
+                    "price": winner.as_ref().map(|(_, price)| U128(*price)),
+                }
+            })
+            .to_string(),
+        );
+
+        match winner {
+            Some((winner_id, price)) => {
+                self.internal_process_purchase(nft_contract_id, token_id, winner_id, price, keeper_id);
+            }
+            None => {
+                self.internal_delete_market_data(&nft_contract_id, &token_id, ListingStatus::Cancelled);
+            }
+        }
+    }
+
+    pub fn get_sealed_bids(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<SealedBid> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let bidder_ids = match self.sealed_bidders.get(&contract_and_token_id) {
+            Some(bidder_ids) => bidder_ids,
+            None => return vec![],
+        };
+        bidder_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|bidder_id| {
+                self.sealed_bids.get(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))
+            })
+            .collect()
+    }
+
+    pub fn get_supply_sealed_bids_by_bidder_id(&self, account_id: AccountId) -> U64 {
+        self.sealed_bids_by_bidder_id
+            .get(&account_id)
+            .map_or(0, |contract_and_token_ids| contract_and_token_ids.len())
+            .into()
+    }
+
+    /// a new commitment takes one storage entry of the bidder's deposit, like an open bid,
+    /// released when the sealed bids of the listing are settled or refunded
+    pub(crate) fn internal_commit_sealed_bid(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        bidder_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        deposit: u128,
+        commitment: Base58CryptoHash,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        assert_eq!(market_data.ft_token_id, ft_token_id, "DS: Payment token does not match the sale");
+        market_data.assert_reserved_for(&bidder_id);
+        let sealed_bid_rules = market_data
+            .sealed_bid_rules
+            .expect("DS: Sale is not a sealed auction");
+        let current_time = env::block_timestamp();
+        assert!(
+            current_time >= market_data.started_at.unwrap(),
+            "DS: Sale has not started yet"
+        );
+        assert!(
+            current_time < sealed_bid_rules.reveal_at.0,
+            "DS: Commit phase has ended"
+        );
+        assert_ne!(
+            market_data.owner_id, bidder_id,
+            "DS: Owner cannot bid their own token"
+        );
+        assert!(
+            deposit >= market_data.price,
+            "DS: Deposit is less than starting price: {:?}",
+            market_data.price
+        );
+
+        let contract_token_and_bidder_id = format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id);
+        // committing again replaces the previous commitment
+        if let Some(previous_bid) = self.sealed_bids.get(&contract_token_and_bidder_id) {
+            self.internal_credit_pending(market_data.ft_token_id.clone(), bidder_id.clone(), previous_bid.deposit.0);
+        } else {
+            let mut bidder_ids = self.sealed_bidders.get(&contract_and_token_id).unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::SealedBiddersInner {
+                    contract_and_token_id_hash: env::sha256_array(contract_and_token_id.as_bytes()),
+                })
+            });
+            assert!(
+                bidder_ids.len() < MAX_SEALED_BIDS,
+                "DS: Sealed auction can take at most {} bids",
+                MAX_SEALED_BIDS
+            );
+            let storage_amount = self.storage_minimum_balance().0;
+            let bidder_paid_storage = self.storage_deposits.get(&bidder_id).unwrap_or(0);
+            let bidder_storage_required =
+                (self.internal_storage_count(&bidder_id) + 1) as u128 * storage_amount;
+            assert!(
+                bidder_paid_storage >= bidder_storage_required,
+                "DS: Insufficient storage paid: {}, for {} entries at {} rate of per entry",
+                bidder_paid_storage,
+                bidder_storage_required / storage_amount,
+                storage_amount
+            );
+            bidder_ids.insert(&bidder_id);
+            self.sealed_bidders.insert(&contract_and_token_id, &bidder_ids);
+
+            let mut contract_and_token_ids = self
+                .sealed_bids_by_bidder_id
+                .get(&bidder_id)
+                .unwrap_or_else(|| {
+                    UnorderedSet::new(StorageKey::SealedBidsByBidderIdInner {
+                        account_id_hash: hash_account_id(&bidder_id),
+                    })
+                });
+            contract_and_token_ids.insert(&contract_and_token_id);
+            self.sealed_bids_by_bidder_id.insert(&bidder_id, &contract_and_token_ids);
+        }
+        self.sealed_bids.insert(
+            &contract_token_and_bidder_id,
+            &SealedBid {
+                bidder_id: bidder_id.clone(),
+                commitment,
+                deposit: deposit.into(),
+                revealed_amount: None,
+                revealed_at: None,
+            },
+        );
+
+        env::log_str(
+            &json!({
+                "type": "commit_sealed_bid",
+                "params": {
+                    "bidder_id": bidder_id,
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "commitment": commitment,
+                    "deposit": U128(deposit),
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub(crate) fn internal_has_sealed_bids(&self, contract_and_token_id: &ContractAndTokenId) -> bool {
+        self.sealed_bidders.get(contract_and_token_id).is_some()
+    }
+
+    pub(crate) fn internal_remove_sealed_bids(&mut self, contract_and_token_id: &ContractAndTokenId) -> Vec<SealedBid> {
+        let mut bidder_ids = match self.sealed_bidders.remove(contract_and_token_id) {
+            Some(bidder_ids) => bidder_ids,
+            None => return vec![],
+        };
+        let sealed_bids: Vec<SealedBid> = bidder_ids
+            .iter()
+            .filter_map(|bidder_id| {
+                self.sealed_bids
+                    .remove(&format!("{}{}{}", contract_and_token_id, DELIMETER, bidder_id))
+            })
+            .collect();
+        bidder_ids.clear();
+        for sealed_bid in sealed_bids.iter() {
+            if let Some(mut contract_and_token_ids) = self.sealed_bids_by_bidder_id.get(&sealed_bid.bidder_id) {
+                contract_and_token_ids.remove(contract_and_token_id);
+                if contract_and_token_ids.is_empty() {
+                    self.sealed_bids_by_bidder_id.remove(&sealed_bid.bidder_id);
+                } else {
+                    self.sealed_bids_by_bidder_id
+                        .insert(&sealed_bid.bidder_id, &contract_and_token_ids);
+                }
+            }
+        }
+        sealed_bids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 100;
+    const REVEAL_AT: u64 = 500;
+    const ENDED_AT: u64 = 1_000;
+
+    fn list_sealed_auction(contract: &mut Marketplace) {
+        let args = MarketArgs {
+            price: Some(U128(PRICE)),
+            is_auction: Some(true),
+            ended_at: Some(U64(ENDED_AT)),
+            sealed_bid_rules: Some(SealedBidRules {
+                reveal_at: U64(REVEAL_AT),
+                pricing: SealedBidPricing::FirstPrice,
+            }),
+            ..Default::default()
+        };
+        list(contract, "seller.near", "1", args);
+    }
+
+    fn commitment(bidder_id: &str, amount: u128, salt: &str) -> Base58CryptoHash {
+        let preimage = format!("{}{}{}{}{}", bidder_id, DELIMETER, amount, DELIMETER, salt);
+        env::sha256_array(preimage.as_bytes()).into()
+    }
+
+    fn commit(contract: &mut Marketplace, bidder_id: &str, amount: u128, deposit: u128) {
+        set_context(bidder_id, deposit, 0);
+        contract.commit_sealed_bid(account(NFT), "1".to_string(), commitment(bidder_id, amount, "salt"));
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Insufficient storage paid")]
+    fn a_commitment_needs_storage() {
+        let mut contract = new_marketplace();
+        list_sealed_auction(&mut contract);
+        commit(&mut contract, "bidder.near", PRICE, PRICE);
+    }
+
+    #[test]
+    fn a_commitment_takes_one_storage_entry_until_settled() {
+        let mut contract = new_marketplace();
+        list_sealed_auction(&mut contract);
+        deposit_storage(&mut contract, "bidder.near");
+        commit(&mut contract, "bidder.near", PRICE, PRICE);
+        // committing again replaces the commitment without another entry
+        commit(&mut contract, "bidder.near", PRICE, 2 * PRICE);
+        assert_eq!(contract.get_supply_sealed_bids_by_bidder_id(account("bidder.near")), U64(1));
+        assert_eq!(pending(&contract, "bidder.near"), PRICE);
+
+        set_context("anyone.near", 1, ENDED_AT + 1);
+        contract.settle_sealed_auction(account(NFT), "1".to_string());
+        assert_eq!(contract.get_supply_sealed_bids_by_bidder_id(account("bidder.near")), U64(0));
+        // unrevealed, the whole deposit is refunded
+        assert_eq!(pending(&contract, "bidder.near"), 3 * PRICE);
+    }
+
+    #[test]
+    fn a_revealed_bid_matching_its_commitment_wins() {
+        let mut contract = new_marketplace();
+        list_sealed_auction(&mut contract);
+        deposit_storage(&mut contract, "bidder.near");
+        commit(&mut contract, "bidder.near", 150, 200);
+
+        set_context("bidder.near", 0, REVEAL_AT);
+        contract.reveal_sealed_bid(account(NFT), "1".to_string(), U128(150), "salt".to_string());
+        let sealed_bid = contract.get_sealed_bids(account(NFT), "1".to_string(), None, None).pop().unwrap();
+        assert_eq!(sealed_bid.revealed_amount, Some(U128(150)));
+
+        set_context("anyone.near", 1, ENDED_AT + 1);
+        contract.settle_sealed_auction(account(NFT), "1".to_string());
+        assert_eq!(pending(&contract, "bidder.near"), 50);
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+        assert_eq!(market_data.lock.unwrap().buyer_id, account("bidder.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Amount and salt do not match the commitment")]
+    fn a_reveal_must_match_the_commitment() {
+        let mut contract = new_marketplace();
+        list_sealed_auction(&mut contract);
+        deposit_storage(&mut contract, "bidder.near");
+        commit(&mut contract, "bidder.near", 150, 200);
+
+        set_context("bidder.near", 0, REVEAL_AT);
+        contract.reveal_sealed_bid(account(NFT), "1".to_string(), U128(160), "salt".to_string());
+    }
+}
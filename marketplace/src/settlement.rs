@@ -0,0 +1,146 @@
+use crate::*;
+
+// a sale reserves 145 Tgas for its approval check, transfer and resolve_purchase, the rest refunds the bids
+const GAS_FOR_SETTLE_LISTING: Gas = Gas::from_tgas(160);
+// expired entries a single call looks at, the ones it can't settle included
+const MAX_SETTLE_SCAN: usize = 50;
+
+#[near_bindgen]
+impl Marketplace {
+    /// anyone can settle an english auction once it ended. It sells to the highest funded bid at or above
+    /// the reserve price, otherwise the sale is cancelled and every bid refunded
+    pub fn settle_auction(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let market_data = self.market.get(&contract_and_token_id).expect("DS: Market data does not exist");
+        assert!(
+            market_data.auction_rules.is_some() || market_data.sealed_bid_rules.is_some(),
+            "DS: Sale is not an auction"
+        );
+        assert!(
+            self.internal_settle_listing(&contract_and_token_id, env::predecessor_account_id()),
+            "DS: Auction has not ended yet"
+        );
+    }
+
+    /// settles up to `limit` ended auctions and removes expired fixed price and dutch sales, oldest first.
+    /// Every sale made this way reserves its own purchase gas, the call stops once the attached gas
+    /// can't cover another one. Returns how many listings were settled or removed
+    pub fn settle_expired(&mut self, limit: Option<u64>) -> U64 {
+        let current_time = env::block_timestamp();
+        let contract_and_token_ids: Vec<ContractAndTokenId> = self
+            .by_ended_at
+            .iter()
+            .take_while(|((ended_at, _), _)| *ended_at < current_time)
+            .take(MAX_SETTLE_SCAN)
+            .map(|((_, contract_and_token_id), _)| contract_and_token_id)
+            .collect();
+
+        let limit = limit.unwrap_or(10);
+        let keeper_id = env::predecessor_account_id();
+        let mut settled = 0;
+        for contract_and_token_id in contract_and_token_ids {
+            if settled >= limit || env::prepaid_gas().saturating_sub(env::used_gas()) < GAS_FOR_SETTLE_LISTING {
+                break;
+            }
+            // listings that are locked or still running don't count against the limit
+            if self.internal_settle_listing(&contract_and_token_id, keeper_id.clone()) {
+                settled += 1;
+            }
+        }
+        U64(settled)
+    }
+
+    /// false while the listing has not ended yet
+    fn internal_settle_listing(&mut self, contract_and_token_id: &ContractAndTokenId, keeper_id: AccountId) -> bool {
+        let mut market_data = match self.market.get(contract_and_token_id) {
+            Some(market_data) => market_data,
+            None => return false,
+        };
+        if self.internal_refresh_status(&mut market_data) != ListingStatus::Ended {
+            return false;
+        }
+        let nft_contract_id = market_data.nft_contract_id.clone();
+        let token_id = market_data.token_id.clone();
+
+        if market_data.sealed_bid_rules.is_some() {
+            self.internal_settle_sealed_auction(nft_contract_id, token_id, Some(keeper_id));
+            return true;
+        }
+        if market_data.auction_rules.is_some() {
+            let reserve_price = market_data.reserve_price.unwrap_or(0);
+            if let Some(bid) = self.internal_pop_funded_bid(&market_data, reserve_price) {
+                self.internal_process_purchase(nft_contract_id, token_id, bid.bidder_id, bid.price.0, Some(keeper_id));
+                return true;
+            }
+        }
+        // nothing to sell, the sale goes away with its storage reservation and any bids are refunded
+        self.internal_delete_market_data(&nft_contract_id, &token_id, ListingStatus::Cancelled);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000_000;
+    const ENDED_AT: u64 = 10;
+
+    fn list_until(contract: &mut Marketplace, token_id: &str, is_auction: bool) {
+        list(
+            contract,
+            "seller.near",
+            token_id,
+            MarketArgs {
+                price: Some(U128(PRICE)),
+                is_auction: Some(is_auction),
+                ended_at: Some(U64(ENDED_AT)),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn settle_expired_sells_ended_auctions_and_rewards_the_keeper() {
+        let mut contract = new_marketplace();
+        set_context("owner.near", 1, 0);
+        contract.set_keeper_reward_bps(1_000);
+        list_until(&mut contract, "1", true);
+        deposit_storage(&mut contract, "bidder.near");
+        set_context("bidder.near", PRICE, 0);
+        contract.add_bid(account(NFT), "1".to_string(), U128(PRICE), None);
+        // the bid came in the extension window
+        let ended_at = market_data_of(&contract, "1").unwrap().ended_at.unwrap();
+
+        set_context("keeper.near", 0, ended_at + 1);
+        assert_eq!(contract.settle_expired(None), U64(1));
+        let market_data = market_data_of(&contract, "1").unwrap();
+        assert_eq!(market_data.status, ListingStatus::Locked);
+        assert_eq!(market_data.lock.as_ref().unwrap().buyer_id, account("bidder.near"));
+
+        set_context_with_results(
+            "market.near",
+            0,
+            ended_at + 1,
+            vec![PromiseResult::Successful(br#"{"payout": {"seller.near": "1000000"}}"#.to_vec())],
+        );
+        contract.resolve_purchase(account("bidder.near"), market_data, U128(PRICE), Some(account("keeper.near")));
+        let fee = PRICE * 250 / 10_000;
+        assert_eq!(pending(&contract, "keeper.near"), fee / 10);
+        assert_eq!(pending(&contract, "treasury.near"), fee - fee / 10);
+        assert_eq!(pending(&contract, "seller.near"), PRICE - fee);
+    }
+
+    #[test]
+    fn settle_expired_removes_ended_sales_and_skips_running_ones() {
+        let mut contract = new_marketplace();
+        list_until(&mut contract, "1", false);
+        list(&mut contract, "seller.near", "2", fixed_price(PRICE));
+
+        set_context("keeper.near", 0, ENDED_AT + 1);
+        assert_eq!(contract.settle_expired(None), U64(1));
+        assert!(market_data_of(&contract, "1").is_none());
+        assert!(market_data_of(&contract, "2").is_some());
+    }
+}
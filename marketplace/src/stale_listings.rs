@@ -0,0 +1,228 @@
+use crate::*;
+
+pub(crate) const GAS_FOR_NFT_IS_APPROVED: Gas = Gas::from_tgas(5);
+const GAS_FOR_RESOLVE_PRUNE_LISTING: Gas = Gas::from_tgas(15);
+// transfers the token and resolves the purchase once the approval checks out
+const GAS_FOR_RESOLVE_PURCHASE_APPROVAL: Gas = Gas::from_tgas(140);
+
+/// notification from NFT contracts that revoke the marketplace's approval of a token,
+/// including the implicit revoke of transferring it
+trait NonFungibleTokenRevokeReceiver {
+    fn nft_on_revoke(&mut self, token_id: TokenId, approval_id: Option<u64>);
+}
+
+#[near_bindgen]
+impl NonFungibleTokenRevokeReceiver for Marketplace {
+    /// `approval_id`, when given, only removes the listing made with that approval
+    fn nft_on_revoke(&mut self, token_id: TokenId, approval_id: Option<u64>) {
+        let nft_contract_id = env::predecessor_account_id();
+        assert!(
+            self.approved_nft_contract_ids.contains(&nft_contract_id),
+            "DS: nft_contract_id is not approved"
+        );
+        let market_data = match self
+            .market
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+        {
+            Some(market_data) => market_data,
+            None => return,
+        };
+        // a listing whose purchase is in flight is restored or invalidated when the transfer resolves
+        if market_data.status == ListingStatus::Locked
+            || approval_id.is_some_and(|approval_id| approval_id != market_data.approval_id)
+        {
+            return;
+        }
+        self.internal_invalidate_listing(&market_data, "nft_on_revoke");
+    }
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// anyone can remove a listing the NFT contract no longer approves the marketplace for,
+    /// the contract's nft_is_approved answer is the proof. Resolves to whether the listing was removed
+    pub fn prune_invalid_listing(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        let market_data = self
+            .market
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            .expect("DS: Market data does not exist");
+        assert_ne!(market_data.status, ListingStatus::Locked, "DS: Sale is being purchased");
+        ext_contract::ext(nft_contract_id.clone())
+            .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+            .nft_is_approved(token_id.clone(), env::current_account_id(), Some(market_data.approval_id))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_PRUNE_LISTING)
+                    .resolve_prune_listing(nft_contract_id, token_id, market_data.approval_id.into()),
+            )
+    }
+
+    #[private]
+    pub fn resolve_prune_listing(&mut self, nft_contract_id: AccountId, token_id: TokenId, approval_id: U64) -> bool {
+        let market_data = match self
+            .market
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+        {
+            // the listing may have been replaced or bought while the check was in flight
+            Some(market_data)
+                if market_data.approval_id == approval_id.0 && market_data.status != ListingStatus::Locked =>
+            {
+                market_data
+            }
+            _ => return false,
+        };
+        if approval_check_result() != Some(false) {
+            return false;
+        }
+        self.internal_invalidate_listing(&market_data, "not_approved");
+        true
+    }
+
+    /// second step of a purchase, the token is only transferred while the stored approval is still valid
+    #[private]
+    pub fn resolve_purchase_approval(
+        &mut self,
+        buyer_id: AccountId,
+        market_data: MarketData,
+        price: U128,
+        keeper_id: Option<AccountId>,
+    ) {
+        // a contract that can't tell is left to fail the transfer itself
+        if approval_check_result() != Some(false) {
+            self.internal_transfer_purchase(market_data, buyer_id, price.0, keeper_id);
+            return;
+        }
+        self.internal_credit_pending(market_data.ft_token_id.clone(), buyer_id, price.0);
+        if self.internal_is_locked(&market_data) {
+            self.internal_invalidate_listing(&market_data, "not_approved");
+        }
+    }
+
+    /// checks the stored approval before transferring a locked listing's token
+    pub(crate) fn internal_check_purchase_approval(
+        &self,
+        market_data: MarketData,
+        buyer_id: AccountId,
+        price: u128,
+        keeper_id: Option<AccountId>,
+    ) -> Promise {
+        ext_contract::ext(market_data.nft_contract_id.clone())
+            .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+            .nft_is_approved(
+                market_data.token_id.clone(),
+                env::current_account_id(),
+                Some(market_data.approval_id),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_PURCHASE_APPROVAL)
+                    .resolve_purchase_approval(buyer_id, market_data, price.into(), keeper_id),
+            )
+    }
+
+    /// removes a listing the marketplace can no longer sell, refunding every bid on it
+    pub(crate) fn internal_invalidate_listing(&mut self, market_data: &MarketData, reason: &str) {
+        self.internal_delete_market_data(&market_data.nft_contract_id, &market_data.token_id, ListingStatus::Cancelled);
+
+        env::log_str(
+            &json!({
+                "type": "listing_invalidated",
+                "params": {
+                    "owner_id": market_data.owner_id,
+                    "nft_contract_id": market_data.nft_contract_id,
+                    "token_id": market_data.token_id,
+                    "approval_id": U64(market_data.approval_id),
+                    "reason": reason,
+                }
+            })
+            .to_string(),
+        );
+    }
+}
+
+/// the answer of the nft_is_approved call this callback follows, None when the call failed
+pub(crate) fn approval_check_result() -> Option<bool> {
+    approval_check_result_at(0)
+}
+
+/// the answer of the `index`th of the nft_is_approved calls this callback joins
+pub(crate) fn approval_check_result_at(index: u64) -> Option<bool> {
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<bool>(&value).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PRICE: u128 = 1_000;
+
+    fn approved(approved: bool) -> Vec<PromiseResult> {
+        vec![PromiseResult::Successful(approved.to_string().into_bytes())]
+    }
+
+    #[test]
+    fn a_revoke_removes_the_listing() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        set_context(NFT, 0, 0);
+        contract.nft_on_revoke("1".to_string(), None);
+        assert!(market_data_of(&contract, "1").is_none());
+    }
+
+    #[test]
+    fn a_revoke_of_another_approval_keeps_the_listing() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        set_context(NFT, 0, 0);
+        contract.nft_on_revoke("1".to_string(), Some(7));
+        assert!(market_data_of(&contract, "1").is_some());
+    }
+
+    #[test]
+    fn a_revoke_leaves_a_locked_listing_to_its_purchase() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        contract.internal_lock_listing(&account(NFT), &"1".to_string(), &account("buyer.near"), PRICE);
+        set_context(NFT, 0, 0);
+        contract.nft_on_revoke("1".to_string(), None);
+        assert_eq!(market_data_of(&contract, "1").unwrap().status, ListingStatus::Locked);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: nft_contract_id is not approved")]
+    fn only_approved_contracts_revoke() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        set_context("other.near", 0, 0);
+        contract.nft_on_revoke("1".to_string(), None);
+    }
+
+    #[test]
+    fn a_prune_removes_only_unapproved_listings() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        set_context_with_results("market.near", 0, 0, approved(true));
+        assert!(!contract.resolve_prune_listing(account(NFT), "1".to_string(), U64(0)));
+        assert!(market_data_of(&contract, "1").is_some());
+
+        set_context_with_results("market.near", 0, 0, approved(false));
+        assert!(contract.resolve_prune_listing(account(NFT), "1".to_string(), U64(0)));
+        assert!(market_data_of(&contract, "1").is_none());
+    }
+
+    #[test]
+    fn a_purchase_of_an_unapproved_listing_is_refunded() {
+        let mut contract = new_marketplace();
+        list(&mut contract, "seller.near", "1", fixed_price(PRICE));
+        let market_data =
+            contract.internal_lock_listing(&account(NFT), &"1".to_string(), &account("buyer.near"), PRICE);
+        set_context_with_results("market.near", 0, 0, approved(false));
+        contract.resolve_purchase_approval(account("buyer.near"), market_data, U128(PRICE), None);
+        assert_eq!(pending(&contract, "buyer.near"), PRICE);
+        assert!(market_data_of(&contract, "1").is_none());
+    }
+}
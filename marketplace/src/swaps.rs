@@ -0,0 +1,494 @@
+use crate::*;
+
+// releasing both tokens, each with its own resolve_release_swap_token
+const GAS_FOR_RESOLVE_SWAP: Gas = Gas::from_tgas(70);
+const GAS_FOR_RESOLVE_RELEASE_SWAP_TOKEN: Gas = Gas::from_tgas(10);
+
+/// msg of nft_on_approve with market_type "swap", the approved token is offered for the requested one
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapArgs {
+    pub requested_nft_contract_id: AccountId,
+    pub requested_token_id: TokenId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub near_amount: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<U64>,
+}
+
+/// msg of nft_on_approve with market_type "accept_swap", the approved token is the requested one
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptSwapArgs {
+    pub swap_id: U64,
+}
+
+/// proposal to trade the offered token, plus near_amount, for the requested token
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Swap {
+    pub swap_id: U64,
+    pub proposer_id: AccountId,
+    pub offered_nft_contract_id: AccountId,
+    pub offered_token_id: TokenId,
+    pub offered_approval_id: U64,
+    pub requested_nft_contract_id: AccountId,
+    pub requested_token_id: TokenId,
+    pub counterparty_id: Option<AccountId>, // None lets whoever owns the requested token accept
+    pub near_amount: U128, // taken from the proposer's NEAR bidding balance
+    pub expires_at: Option<U64>,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// withdraws a proposal, its NEAR goes to the proposer's pending withdrawals
+    #[payable]
+    pub fn delete_swap(&mut self, swap_id: U64) {
+        assert_one_yocto();
+        let swap = self.swaps.get(&swap_id.0).expect("DS: Swap does not exist");
+        assert!(
+            [swap.proposer_id.clone(), self.owner_id.clone()].contains(&env::predecessor_account_id()),
+            "DS: Proposer or owner only"
+        );
+        self.internal_delete_swap(&swap);
+        self.internal_credit_pending(None, swap.proposer_id.clone(), swap.near_amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "delete_swap",
+                "params": {
+                    "swap_id": swap_id,
+                    "proposer_id": swap.proposer_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// hands out a swapped token whose release from the marketplace failed, to the account it is held for
+    #[payable]
+    pub fn claim_swap_token(&mut self, nft_contract_id: AccountId, token_id: TokenId) {
+        assert_one_yocto();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let receiver_id = self
+            .escrowed_tokens
+            .get(&contract_and_token_id)
+            .expect("DS: Token is not held by the marketplace");
+        assert_eq!(receiver_id, env::predecessor_account_id(), "DS: Token is held for another account");
+        // a failed release records the token again
+        self.escrowed_tokens.remove(&contract_and_token_id);
+        self.internal_release_swap_token(&nft_contract_id, &token_id, &receiver_id);
+    }
+
+    /// the account a swapped token is held for after its release failed
+    pub fn get_escrowed_token(&self, nft_contract_id: AccountId, token_id: TokenId) -> Option<AccountId> {
+        self.escrowed_tokens
+            .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+    }
+
+    pub fn get_swap(&self, swap_id: U64) -> Option<Swap> {
+        self.swaps.get(&swap_id.0)
+    }
+
+    pub fn get_swaps_by_proposer_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Swap> {
+        self.internal_swaps_page(self.swaps_by_proposer_id.get(&account_id), from_index, limit)
+    }
+
+    /// proposals naming the account as counterparty
+    pub fn get_swaps_by_counterparty_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Swap> {
+        self.internal_swaps_page(self.swaps_by_counterparty_id.get(&account_id), from_index, limit)
+    }
+
+    pub fn get_supply_swaps_by_proposer_id(&self, account_id: AccountId) -> U64 {
+        self.swaps_by_proposer_id
+            .get(&account_id)
+            .map_or(0, |swap_ids| swap_ids.len())
+            .into()
+    }
+
+    /// both tokens were moved to the marketplace. They are handed to their new owners when both arrived,
+    /// otherwise the one that arrived goes back and the proposer's NEAR is refunded
+    #[private]
+    pub fn resolve_swap(&mut self, swap: Swap, counterparty_id: AccountId) {
+        let offered_moved = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        let requested_moved = matches!(env::promise_result(1), PromiseResult::Successful(_));
+
+        if offered_moved && requested_moved {
+            self.internal_release_swap_token(&swap.offered_nft_contract_id, &swap.offered_token_id, &counterparty_id);
+            self.internal_release_swap_token(&swap.requested_nft_contract_id, &swap.requested_token_id, &swap.proposer_id);
+            self.internal_credit_pending(None, counterparty_id.clone(), swap.near_amount.0);
+
+            env::log_str(
+                &json!({
+                    "type": "resolve_swap",
+                    "params": {
+                        "swap": swap,
+                        "counterparty_id": counterparty_id,
+                    }
+                })
+                .to_string(),
+            );
+            return;
+        }
+
+        // owners get back whatever already reached the marketplace
+        if offered_moved {
+            self.internal_release_swap_token(&swap.offered_nft_contract_id, &swap.offered_token_id, &swap.proposer_id);
+        }
+        if requested_moved {
+            self.internal_release_swap_token(&swap.requested_nft_contract_id, &swap.requested_token_id, &counterparty_id);
+        }
+        self.internal_credit_pending(None, swap.proposer_id.clone(), swap.near_amount.0);
+
+        env::log_str(
+            &json!({
+                "type": "swap_failed",
+                "params": {
+                    "swap": swap,
+                    "counterparty_id": counterparty_id,
+                    "offered_token_moved": offered_moved,
+                    "requested_token_moved": requested_moved,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    /// keeps a token the marketplace failed to release, `receiver_id` can claim it with claim_swap_token
+    #[private]
+    pub fn resolve_release_swap_token(&mut self, nft_contract_id: AccountId, token_id: TokenId, receiver_id: AccountId) {
+        if is_promise_success() {
+            return;
+        }
+        self.escrowed_tokens
+            .insert(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id), &receiver_id);
+
+        env::log_str(
+            &json!({
+                "type": "swap_release_failed",
+                "params": {
+                    "nft_contract_id": nft_contract_id,
+                    "token_id": token_id,
+                    "receiver_id": receiver_id,
+                }
+            })
+            .to_string(),
+        );
+    }
+
+    pub(crate) fn internal_propose_swap(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        args: SwapArgs,
+    ) {
+        assert!(
+            self.approved_nft_contract_ids.contains(&args.requested_nft_contract_id),
+            "DS: requested_nft_contract_id is not approved"
+        );
+        assert!(
+            args.requested_nft_contract_id != nft_contract_id || args.requested_token_id != token_id,
+            "DS: Cannot swap a token for itself"
+        );
+        self.internal_assert_not_bundled(&nft_contract_id, &token_id);
+        assert!(
+            args.counterparty_id.as_ref() != Some(&owner_id),
+            "DS: Cannot swap with yourself"
+        );
+        if let Some(expires_at) = args.expires_at {
+            assert!(
+                expires_at.0 > env::block_timestamp(),
+                "DS: Swap expiry must be in the future"
+            );
+        }
+
+        let storage_amount = self.storage_minimum_balance().0;
+        let owner_paid_storage = self.storage_deposits.get(&owner_id).unwrap_or(0);
+        let owner_storage_required =
+            (self.internal_storage_count(&owner_id) + 1) as u128 * storage_amount;
+        if owner_paid_storage < owner_storage_required {
+            let notif = format!(
+                "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
+                owner_paid_storage,
+                owner_storage_required / storage_amount,
+                storage_amount
+            );
+            env::log_str(&notif);
+            return;
+        }
+
+        let near_amount = args.near_amount.map_or(0, |x| x.0);
+        if near_amount > 0 {
+            self.internal_debit_bidding_balance(&owner_id, &None, near_amount);
+        }
+
+        let swap_id = self.next_swap_id;
+        self.next_swap_id += 1;
+        let swap = Swap {
+            swap_id: swap_id.into(),
+            proposer_id: owner_id,
+            offered_nft_contract_id: nft_contract_id,
+            offered_token_id: token_id,
+            offered_approval_id: approval_id.into(),
+            requested_nft_contract_id: args.requested_nft_contract_id,
+            requested_token_id: args.requested_token_id,
+            counterparty_id: args.counterparty_id,
+            near_amount: near_amount.into(),
+            expires_at: args.expires_at,
+        };
+        self.swaps.insert(&swap_id, &swap);
+        insert_swap_id(
+            &mut self.swaps_by_proposer_id,
+            &swap.proposer_id,
+            swap_id,
+            StorageKey::SwapsByProposerIdInner {
+                account_id_hash: hash_account_id(&swap.proposer_id),
+            },
+        );
+        if let Some(counterparty_id) = &swap.counterparty_id {
+            insert_swap_id(
+                &mut self.swaps_by_counterparty_id,
+                counterparty_id,
+                swap_id,
+                StorageKey::SwapsByCounterpartyIdInner {
+                    account_id_hash: hash_account_id(counterparty_id),
+                },
+            );
+        }
+
+        env::log_str(
+            &json!({
+                "type": "add_swap",
+                "params": swap,
+            })
+            .to_string(),
+        );
+    }
+
+    /// moves both tokens to the marketplace, resolve_swap takes it from there
+    pub(crate) fn internal_accept_swap(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        swap_id: U64,
+    ) -> Promise {
+        let swap = self.swaps.get(&swap_id.0).expect("DS: Swap does not exist");
+        assert!(
+            swap.requested_nft_contract_id == nft_contract_id && swap.requested_token_id == token_id,
+            "DS: Token is not the one the swap asks for"
+        );
+        assert_ne!(swap.proposer_id, owner_id, "DS: Cannot accept your own swap");
+        if let Some(counterparty_id) = &swap.counterparty_id {
+            assert_eq!(*counterparty_id, owner_id, "DS: Swap is proposed to another account");
+        }
+        assert!(
+            swap.expires_at.is_none_or(|expires_at| env::block_timestamp() <= expires_at.0),
+            "DS: Swap has expired"
+        );
+        // either token may have been bundled since the swap was proposed
+        self.internal_assert_not_bundled(&swap.offered_nft_contract_id, &swap.offered_token_id);
+        self.internal_assert_not_bundled(&nft_contract_id, &token_id);
+        self.internal_delete_swap(&swap);
+
+        ext_contract::ext(swap.offered_nft_contract_id.clone())
+            .with_attached_deposit(ONE_YOCTONEAR)
+            .with_static_gas(GAS_FOR_NFT_TRANSFER)
+            .nft_transfer(
+                env::current_account_id(),
+                swap.offered_token_id.clone(),
+                Some(swap.offered_approval_id.0),
+            )
+            .and(
+                ext_contract::ext(nft_contract_id)
+                    .with_attached_deposit(ONE_YOCTONEAR)
+                    .with_static_gas(GAS_FOR_NFT_TRANSFER)
+                    .nft_transfer(env::current_account_id(), token_id, Some(approval_id)),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_SWAP)
+                    .resolve_swap(swap, owner_id),
+            )
+    }
+
+    /// the marketplace owns the token at this point, so the transfer needs no approval
+    fn internal_release_swap_token(&self, nft_contract_id: &AccountId, token_id: &TokenId, receiver_id: &AccountId) {
+        ext_contract::ext(nft_contract_id.clone())
+            .with_attached_deposit(ONE_YOCTONEAR)
+            .with_static_gas(GAS_FOR_NFT_TRANSFER)
+            .nft_transfer(receiver_id.clone(), token_id.clone(), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_RELEASE_SWAP_TOKEN)
+                    .resolve_release_swap_token(nft_contract_id.clone(), token_id.clone(), receiver_id.clone()),
+            );
+    }
+
+    fn internal_delete_swap(&mut self, swap: &Swap) {
+        self.swaps.remove(&swap.swap_id.0);
+        remove_swap_id(&mut self.swaps_by_proposer_id, &swap.proposer_id, swap.swap_id.0);
+        if let Some(counterparty_id) = &swap.counterparty_id {
+            remove_swap_id(&mut self.swaps_by_counterparty_id, counterparty_id, swap.swap_id.0);
+        }
+    }
+
+    fn internal_swaps_page(
+        &self,
+        swap_ids: Option<UnorderedSet<u64>>,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<Swap> {
+        let swap_ids = match swap_ids {
+            Some(swap_ids) => swap_ids,
+            None => return vec![],
+        };
+        swap_ids
+            .iter()
+            .skip(from_index.map_or(0, |x| x.0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .filter_map(|swap_id| self.swaps.get(&swap_id))
+            .collect()
+    }
+}
+
+/// `inner_key` prefixes the account's set when it has none yet
+fn insert_swap_id(
+    swaps_by_account_id: &mut LookupMap<AccountId, UnorderedSet<u64>>,
+    account_id: &AccountId,
+    swap_id: u64,
+    inner_key: StorageKey,
+) {
+    let mut swap_ids = swaps_by_account_id
+        .get(account_id)
+        .unwrap_or_else(|| UnorderedSet::new(inner_key));
+    swap_ids.insert(&swap_id);
+    swaps_by_account_id.insert(account_id, &swap_ids);
+}
+
+fn remove_swap_id(swaps_by_account_id: &mut LookupMap<AccountId, UnorderedSet<u64>>, account_id: &AccountId, swap_id: u64) {
+    if let Some(mut swap_ids) = swaps_by_account_id.get(account_id) {
+        swap_ids.remove(&swap_id);
+        if swap_ids.is_empty() {
+            swaps_by_account_id.remove(account_id);
+        } else {
+            swaps_by_account_id.insert(account_id, &swap_ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const NEAR_AMOUNT: u128 = 500;
+
+    fn propose_test_swap(contract: &mut Marketplace) -> Swap {
+        set_context("alice.near", NEAR_AMOUNT, 0);
+        contract.deposit_bidding_balance();
+        deposit_storage(contract, "alice.near");
+        let args = SwapArgs {
+            requested_nft_contract_id: account(NFT),
+            requested_token_id: "2".to_string(),
+            counterparty_id: Some(account("bob.near")),
+            near_amount: Some(U128(NEAR_AMOUNT)),
+            expires_at: None,
+        };
+        let mut msg = near_sdk::serde_json::to_value(&args).unwrap();
+        msg["market_type"] = json!("swap");
+        approve(contract, "alice.near", "1", 0, &msg.to_string());
+        contract.get_swap(U64(0)).unwrap()
+    }
+
+    fn accept_msg() -> String {
+        json!({ "market_type": "accept_swap", "swap_id": U64(0) }).to_string()
+    }
+
+    #[test]
+    fn a_proposal_escrows_the_near_amount() {
+        let mut contract = new_marketplace();
+        let swap = propose_test_swap(&mut contract);
+        assert_eq!(swap.near_amount, U128(NEAR_AMOUNT));
+        assert_eq!(contract.get_bidding_balance(account("alice.near"), None).balance, U128(0));
+        assert_eq!(contract.get_supply_swaps_by_proposer_id(account("alice.near")), U64(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Swap is proposed to another account")]
+    fn only_the_counterparty_accepts() {
+        let mut contract = new_marketplace();
+        propose_test_swap(&mut contract);
+        approve(&mut contract, "carol.near", "2", 0, &accept_msg());
+    }
+
+    #[test]
+    fn a_completed_swap_pays_the_counterparty() {
+        let mut contract = new_marketplace();
+        let swap = propose_test_swap(&mut contract);
+        approve(&mut contract, "bob.near", "2", 0, &accept_msg());
+        assert!(contract.get_swap(U64(0)).is_none());
+
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Successful(vec![])],
+        );
+        contract.resolve_swap(swap, account("bob.near"));
+        assert_eq!(pending(&contract, "bob.near"), NEAR_AMOUNT);
+        assert_eq!(pending(&contract, "alice.near"), 0);
+    }
+
+    #[test]
+    fn a_failed_swap_refunds_the_proposer() {
+        let mut contract = new_marketplace();
+        let swap = propose_test_swap(&mut contract);
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        contract.resolve_swap(swap, account("bob.near"));
+        assert_eq!(pending(&contract, "alice.near"), NEAR_AMOUNT);
+        assert_eq!(pending(&contract, "bob.near"), 0);
+    }
+
+    #[test]
+    fn a_token_whose_release_failed_is_claimed_by_its_receiver() {
+        let mut contract = new_marketplace();
+        set_context_with_results("market.near", 0, 0, vec![PromiseResult::Failed]);
+        contract.resolve_release_swap_token(account(NFT), "1".to_string(), account("bob.near"));
+        assert_eq!(contract.get_escrowed_token(account(NFT), "1".to_string()), Some(account("bob.near")));
+
+        set_context("bob.near", 1, 0);
+        contract.claim_swap_token(account(NFT), "1".to_string());
+        assert!(contract.get_escrowed_token(account(NFT), "1".to_string()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: Token is held for another account")]
+    fn a_held_token_cannot_be_claimed_by_someone_else() {
+        let mut contract = new_marketplace();
+        set_context_with_results("market.near", 0, 0, vec![PromiseResult::Failed]);
+        contract.resolve_release_swap_token(account(NFT), "1".to_string(), account("bob.near"));
+        set_context("carol.near", 1, 0);
+        contract.claim_swap_token(account(NFT), "1".to_string());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::*;
+
+// bounds a single sweep on top of what its attached gas allows
+pub const MAX_SWEEP_ITEMS: usize = 10;
+// listings a sweep looks at, passed over ones included
+const MAX_SWEEP_SCAN: usize = 50;
+
+const GAS_FOR_RESOLVE_SWEEP: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE_SWEEP_APPROVALS: Gas = Gas::from_tgas(10);
+// crediting one sale, or checking the approval of a failed one before restoring its listing
+const GAS_FOR_RESOLVE_SWEEP_ITEM: Gas = Gas::from_tgas(25);
+// locking the sale and scheduling its approval check
+const GAS_FOR_SWEEP_DISPATCH: Gas = Gas::from_tgas(5);
+// scheduling the transfer of an approved sale
+const GAS_FOR_SWEEP_TRANSFER_DISPATCH: Gas = Gas::from_tgas(3);
+const GAS_FOR_SWEEP_RESERVE: Gas = Gas::from_tgas(10);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SweepToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+/// what a sweep buys, the cheapest fixed price sales of a collection or the listed tokens
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum SweepTarget {
+    Floor {
+        nft_contract_id: AccountId,
+        count: u32,
+    },
+    Tokens {
+        tokens: Vec<SweepToken>,
+    },
+}
+
+/// a sale locked by a sweep, waiting for its transfer
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SweptSale {
+    pub market_data: MarketData,
+    pub price: U128,
+}
+
+#[near_bindgen]
+impl Marketplace {
+    /// buys as much of `target` as `max_total` (the whole deposit when None) and the attached gas allow.
+    /// Sales the buyer can't buy outright are passed over, what is not spent goes to pending withdrawals
+    #[payable]
+    pub fn sweep(&mut self, target: SweepTarget, max_total: Option<U128>) {
+        self.internal_sweep(
+            target,
+            env::predecessor_account_id(),
+            None,
+            env::attached_deposit().as_yoctonear(),
+            max_total,
+        );
+    }
+
+    /// settles every transfer of a sweep at once, failed ones are refunded with the unspent amount and their
+    /// listings restored, tokens with a rejected payout are refunded too. Returns what the fungible token contract has to refund through ft_resolve_transfer
+    #[private]
+    pub fn resolve_sweep(
+        &mut self,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        sales: Vec<SweptSale>,
+        unspent: U128,
+    ) -> U128 {
+        let mut refund = unspent.0;
+        let mut bought = vec![];
+        let mut failed = vec![];
+        let mut rejected = vec![];
+        for (i, sale) in sales.into_iter().enumerate() {
+            let token = json!({
+                "owner_id": sale.market_data.owner_id,
+                "nft_contract_id": sale.market_data.nft_contract_id,
+                "token_id": sale.market_data.token_id,
+                "price": sale.price,
+            });
+            match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => {
+                    self.internal_settle_locked_listing(&sale.market_data);
+                    let owner_id = &sale.market_data.owner_id;
+                    match self.internal_split_payout(&value, sale.price.0, owner_id) {
+                        Some(payout) => {
+                            self.internal_credit_sale(&ft_token_id, owner_id, sale.price.0, payout, None);
+                            bought.push(token);
+                        }
+                        // the token has moved but its payout is rejected, the buyer gets its price back
+                        None => {
+                            refund += sale.price.0;
+                            rejected.push(token);
+                        }
+                    }
+                }
+                _ => {
+                    self.internal_restore_listing(&sale.market_data);
+                    refund += sale.price.0;
+                    failed.push(token);
+                }
+            }
+        }
+
+        env::log_str(
+            &json!({
+                "type": "resolve_sweep",
+                "params": {
+                    "buyer_id": buyer_id,
+                    "ft_token_id": ft_token_id,
+                    "bought": bought,
+                    "failed": failed,
+                    "rejected": rejected,
+                    "refund": U128(refund),
+                }
+            })
+            .to_string(),
+        );
+
+        self.internal_refund_sweep(buyer_id, ft_token_id, refund)
+    }
+
+    /// transfers the sales whose approval checked out, the others are invalidated and refunded
+    #[private]
+    pub fn resolve_sweep_approvals(
+        &mut self,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        sales: Vec<SweptSale>,
+        unspent: U128,
+    ) -> PromiseOrValue<U128> {
+        let mut unspent = unspent.0;
+        let mut approved: Vec<SweptSale> = vec![];
+        let mut transfers: Option<Promise> = None;
+        for (i, sale) in sales.into_iter().enumerate() {
+            // a contract that can't tell is left to fail the transfer itself
+            if approval_check_result_at(i as u64) == Some(false) {
+                if self.internal_is_locked(&sale.market_data) {
+                    self.internal_invalidate_listing(&sale.market_data, "not_approved");
+                }
+                unspent += sale.price.0;
+                continue;
+            }
+            let transfer = ext_contract::ext(sale.market_data.nft_contract_id.clone())
+                .with_attached_deposit(ONE_YOCTONEAR)
+                .with_static_gas(GAS_FOR_NFT_TRANSFER)
+                .nft_transfer_payout(
+                    buyer_id.clone(),
+                    sale.market_data.token_id.clone(),
+                    Some(sale.market_data.approval_id),
+                    None,
+                    sale.price,
+                    Some(self.max_len_payout),
+                );
+            transfers = Some(match transfers {
+                Some(transfers) => transfers.and(transfer),
+                None => transfer,
+            });
+            approved.push(sale);
+        }
+
+        let transfers = match transfers {
+            Some(transfers) => transfers,
+            None => return PromiseOrValue::Value(self.internal_refund_sweep(buyer_id, ft_token_id, unspent)),
+        };
+        let resolve_gas =
+            GAS_FOR_RESOLVE_SWEEP.saturating_add(GAS_FOR_RESOLVE_SWEEP_ITEM.saturating_mul(approved.len() as u64));
+        PromiseOrValue::Promise(
+            transfers.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(resolve_gas)
+                    .resolve_sweep(buyer_id, ft_token_id, approved, U128(unspent)),
+            ),
+        )
+    }
+
+    /// `ft_token_id` is the currency the payment arrived in, None for NEAR
+    pub(crate) fn internal_sweep(
+        &mut self,
+        target: SweepTarget,
+        buyer_id: AccountId,
+        ft_token_id: Option<AccountId>,
+        amount: u128,
+        max_total: Option<U128>,
+    ) -> Promise {
+        let gas_per_item = GAS_FOR_NFT_IS_APPROVED
+            .saturating_add(GAS_FOR_NFT_TRANSFER)
+            .saturating_add(GAS_FOR_RESOLVE_SWEEP_ITEM)
+            .saturating_add(GAS_FOR_SWEEP_DISPATCH)
+            .saturating_add(GAS_FOR_SWEEP_TRANSFER_DISPATCH);
+        let gas_for_items = env::prepaid_gas()
+            .saturating_sub(env::used_gas())
+            .saturating_sub(GAS_FOR_RESOLVE_SWEEP_APPROVALS)
+            .saturating_sub(GAS_FOR_RESOLVE_SWEEP)
+            .saturating_sub(GAS_FOR_SWEEP_RESERVE);
+        let mut max_items = MAX_SWEEP_ITEMS.min((gas_for_items.as_gas() / gas_per_item.as_gas()) as usize);
+
+        let (candidates, is_floor): (Vec<(AccountId, TokenId)>, bool) = match target {
+            SweepTarget::Floor { nft_contract_id, count } => {
+                assert!(count > 0, "DS: count must be greater than 0");
+                max_items = max_items.min(count as usize);
+                let token_ids: Vec<TokenId> = self
+                    .by_price
+                    .get(&collection_and_currency_id(&nft_contract_id, &ft_token_id))
+                    .map_or(vec![], |by_price| {
+                        by_price.iter().take(MAX_SWEEP_SCAN).map(|((_, token_id), _)| token_id).collect()
+                    });
+                (
+                    token_ids
+                        .into_iter()
+                        .map(|token_id| (nft_contract_id.clone(), token_id))
+                        .collect(),
+                    true,
+                )
+            }
+            SweepTarget::Tokens { tokens } => {
+                assert!(
+                    !tokens.is_empty() && tokens.len() <= MAX_SWEEP_SCAN,
+                    "DS: A sweep lists 1 to {} tokens",
+                    MAX_SWEEP_SCAN
+                );
+                (
+                    tokens
+                        .into_iter()
+                        .map(|token| (token.nft_contract_id, token.token_id))
+                        .collect(),
+                    false,
+                )
+            }
+        };
+
+        let mut budget = max_total.map_or(amount, |max_total| max_total.0.min(amount));
+        let mut sales: Vec<SweptSale> = vec![];
+        let mut checks: Option<Promise> = None;
+        for (nft_contract_id, token_id) in candidates {
+            if sales.len() >= max_items {
+                break;
+            }
+            let mut market_data = match self
+                .market
+                .get(&format!("{}{}{}", nft_contract_id, DELIMETER, token_id))
+            {
+                Some(market_data) => market_data,
+                None => continue,
+            };
+            let price = match self.internal_sweep_price(&mut market_data, &buyer_id, &ft_token_id) {
+                Some(price) => price,
+                None => continue,
+            };
+            if price > budget {
+                // the floor only gets more expensive from here
+                if is_floor {
+                    break;
+                }
+                continue;
+            }
+            budget -= price;
+
+            // a sweep paid in a fungible token that never resolves is refunded by its ft_resolve_transfer
+            let escrow = if ft_token_id.is_some() { 0 } else { price };
+            let market_data = self.internal_lock_listing(&nft_contract_id, &token_id, &buyer_id, escrow);
+            let check = ext_contract::ext(nft_contract_id)
+                .with_static_gas(GAS_FOR_NFT_IS_APPROVED)
+                .nft_is_approved(token_id, env::current_account_id(), Some(market_data.approval_id));
+            checks = Some(match checks {
+                Some(checks) => checks.and(check),
+                None => check,
+            });
+            sales.push(SweptSale {
+                market_data,
+                price: price.into(),
+            });
+        }
+
+        let checks = checks.expect("DS: No sale to sweep within the budget");
+        let spent: u128 = sales.iter().map(|sale| sale.price.0).sum();
+        let resolve_gas = GAS_FOR_RESOLVE_SWEEP_APPROVALS.saturating_add(GAS_FOR_RESOLVE_SWEEP).saturating_add(
+            GAS_FOR_SWEEP_TRANSFER_DISPATCH
+                .saturating_add(GAS_FOR_NFT_TRANSFER)
+                .saturating_add(GAS_FOR_RESOLVE_SWEEP_ITEM)
+                .saturating_mul(sales.len() as u64),
+        );
+        checks.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(resolve_gas)
+                .resolve_sweep_approvals(buyer_id, ft_token_id, sales, U128(amount - spent)),
+        )
+    }
+
+    /// NEAR goes to pending withdrawals, fungible tokens are returned to be refunded by their contract
+    fn internal_refund_sweep(&mut self, buyer_id: AccountId, ft_token_id: Option<AccountId>, refund: u128) -> U128 {
+        if ft_token_id.is_some() {
+            return U128(refund);
+        }
+        self.internal_credit_pending(None, buyer_id, refund);
+        U128(0)
+    }
+
+    /// what the buyer pays for the sale in a sweep, None when the sweep has to pass it over
+    fn internal_sweep_price(
+        &mut self,
+        market_data: &mut MarketData,
+        buyer_id: &AccountId,
+        ft_token_id: &Option<AccountId>,
+    ) -> Option<u128> {
+        if self.internal_refresh_status(market_data) != ListingStatus::Active
+            || market_data.owner_id == *buyer_id
+            || market_data.ft_token_id != *ft_token_id
+            || market_data.sealed_bid_rules.is_some()
+            // gated sales need their own holder check
+            || market_data.holder_gate.is_some()
+            || !market_data.is_reserved_for(buyer_id)
+        {
+            return None;
+        }
+        // english auctions can only be bought outright at their buy now price
+        if market_data.auction_rules.is_some() {
+            return market_data.buy_now_price;
+        }
+        Some(self.internal_current_price(market_data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn list_floor(contract: &mut Marketplace) {
+        for (token_id, price) in [("1", 100), ("2", 200), ("3", 300)] {
+            list(contract, "seller.near", token_id, fixed_price(price));
+        }
+    }
+
+    fn floor(count: u32) -> SweepTarget {
+        SweepTarget::Floor {
+            nft_contract_id: account(NFT),
+            count,
+        }
+    }
+
+    #[test]
+    fn a_sweep_locks_the_floor_up_to_max_total() {
+        let mut contract = new_marketplace();
+        list_floor(&mut contract);
+        set_context("buyer.near", 600, 0);
+        contract.sweep(floor(3), Some(U128(300)));
+        assert_eq!(market_data_of(&contract, "1").unwrap().status, ListingStatus::Locked);
+        assert_eq!(market_data_of(&contract, "2").unwrap().status, ListingStatus::Locked);
+        assert_eq!(market_data_of(&contract, "3").unwrap().status, ListingStatus::Active);
+    }
+
+    #[test]
+    #[should_panic(expected = "DS: No sale to sweep within the budget")]
+    fn a_sweep_needs_a_sale_within_its_budget() {
+        let mut contract = new_marketplace();
+        list_floor(&mut contract);
+        set_context("buyer.near", 600, 0);
+        contract.sweep(floor(3), Some(U128(50)));
+    }
+
+    #[test]
+    fn resolve_sweep_refunds_failed_transfers_and_the_unspent_amount() {
+        let mut contract = new_marketplace();
+        list_floor(&mut contract);
+        set_context("buyer.near", 600, 0);
+        contract.sweep(floor(3), Some(U128(300)));
+        let sales = ["1", "2"]
+            .iter()
+            .map(|token_id| {
+                let market_data = market_data_of(&contract, token_id).unwrap();
+                let price = U128(market_data.price);
+                SweptSale { market_data, price }
+            })
+            .collect();
+
+        set_context_with_results(
+            "market.near",
+            0,
+            0,
+            vec![
+                PromiseResult::Successful(br#"{"payout": {"seller.near": "100"}}"#.to_vec()),
+                PromiseResult::Failed,
+            ],
+        );
+        let refund = contract.resolve_sweep(account("buyer.near"), None, sales, U128(300));
+        assert_eq!(refund, U128(0));
+        assert_eq!(pending(&contract, "buyer.near"), 300 + 200);
+        assert!(market_data_of(&contract, "1").is_none());
+        assert_eq!(pending(&contract, "seller.near"), 100 - 100 * 250 / 10_000);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::*;
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::{test_vm_config, testing_env, RuntimeFeesConfig};
+
+/// shared setup of the unit tests, a marketplace at market.near trading nft.near tokens
+pub(crate) const NFT: &str = "nft.near";
+
+pub(crate) fn account(name: &str) -> AccountId {
+    name.parse().unwrap()
+}
+
+/// `predecessor_id` calls, and signs, with `deposit` attached at `timestamp`
+pub(crate) fn set_context(predecessor_id: &str, deposit: u128, timestamp: u64) {
+    set_context_with_results(predecessor_id, deposit, timestamp, vec![]);
+}
+
+/// as set_context, for a callback that sees `promise_results`
+pub(crate) fn set_context_with_results(
+    predecessor_id: &str,
+    deposit: u128,
+    timestamp: u64,
+    promise_results: Vec<PromiseResult>,
+) {
+    let context = VMContextBuilder::new()
+        .current_account_id(account("market.near"))
+        .predecessor_account_id(account(predecessor_id))
+        .signer_account_id(account(predecessor_id))
+        .attached_deposit(NearToken::from_yoctonear(deposit))
+        .block_timestamp(timestamp)
+        .build();
+    testing_env!(
+        context,
+        test_vm_config(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        promise_results
+    );
+}
+
+/// a marketplace owned by owner.near with a 2.5% fee paid to treasury.near
+pub(crate) fn new_marketplace() -> Marketplace {
+    set_context("owner.near", 0, 0);
+    Marketplace::new(
+        account("owner.near"),
+        account("treasury.near"),
+        Some(vec![account(NFT)]),
+        None,
+        250,
+    )
+}
+
+/// `account_id` pays the storage of one more listing, offer or bid
+pub(crate) fn deposit_storage(contract: &mut Marketplace, account_id: &str) {
+    set_context(account_id, STORAGE_ADD_MARKET_DATA, 0);
+    contract.storage_deposit(None);
+}
+
+/// `owner_id` pays the storage of one more listing and approves `token_id` with `args`
+pub(crate) fn list(contract: &mut Marketplace, owner_id: &str, token_id: &str, args: MarketArgs) {
+    deposit_storage(contract, owner_id);
+    approve(contract, owner_id, token_id, 0, &near_sdk::serde_json::to_string(&args).unwrap());
+}
+
+/// nft.near calls nft_on_approve for `owner_id` with `msg`, at the time of the current context
+pub(crate) fn approve(contract: &mut Marketplace, owner_id: &str, token_id: &str, approval_id: u64, msg: &str) {
+    let context = VMContextBuilder::new()
+        .current_account_id(account("market.near"))
+        .predecessor_account_id(account(NFT))
+        .signer_account_id(account(owner_id))
+        .block_timestamp(env::block_timestamp())
+        .build();
+    testing_env!(context);
+    contract.nft_on_approve(token_id.to_string(), account(owner_id), approval_id, msg.to_string());
+}
+
+pub(crate) fn fixed_price(price: u128) -> MarketArgs {
+    MarketArgs {
+        price: Some(U128(price)),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn market_data_of(contract: &Marketplace, token_id: &str) -> Option<MarketData> {
+    contract.market.get(&format!("{}{}{}", NFT, DELIMETER, token_id))
+}
+
+pub(crate) fn pending(contract: &Marketplace, account_id: &str) -> u128 {
+    contract.get_pending_withdrawal(account(account_id), None).0
+}
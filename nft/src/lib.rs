@@ -36,7 +36,7 @@ use near_sdk::{
     env, near_bindgen, require, AccountId, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, NearToken, Gas, 
     serde_json::json,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 mod ft_balances;
 
@@ -568,6 +568,88 @@ impl NonFungibleTokenApproval for Contract {
     }
 }
 
+const GAS_FOR_NFT_BATCH_APPROVE: Gas = Gas::from_tgas(10);
+
+/// receiver of the approvals made by nft_batch_approve, e.g. the marketplace
+#[near_sdk::ext_contract(ext_nft_batch_approval_receiver)]
+pub trait NonFungibleTokenBatchApprovalsReceiver {
+    fn nft_on_batch_approve(
+        &mut self,
+        tokens: Vec<TokenId>,
+        approval_ids: Vec<u64>,
+        owner_id: AccountId,
+        msg: String,
+    );
+}
+
+#[near_bindgen]
+impl Contract {
+    /// nft_approve for several tokens of the predecessor at once. The attached deposit covers the storage
+    /// of every new approval and `msg`, when given, is passed to a single nft_on_batch_approve call
+    #[payable]
+    pub fn nft_batch_approve(
+        &mut self,
+        token_ids: Vec<TokenId>,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) -> Option<Promise> {
+        require!(
+            env::attached_deposit() >= NearToken::from_yoctonear(1),
+            "Requires attached deposit of at least 1 yoctoNEAR"
+        );
+        require!(!token_ids.is_empty(), "token_ids must not be empty");
+        let unique_token_ids: HashSet<&TokenId> = token_ids.iter().collect();
+        require!(unique_token_ids.len() == token_ids.len(), "token_ids must be unique");
+
+        let owner_id = env::predecessor_account_id();
+        let approvals_by_id = self
+            .tokens
+            .approvals_by_id
+            .as_mut()
+            .unwrap_or_else(|| env::panic_str("NFT does not support Approval Management"));
+        let next_approval_id_by_id = self
+            .tokens
+            .next_approval_id_by_id
+            .as_mut()
+            .unwrap_or_else(|| env::panic_str("next_approval_by_id must be set for approval ext"));
+
+        let mut approval_ids = Vec::with_capacity(token_ids.len());
+        let mut storage_used: u64 = 0;
+        for token_id in token_ids.iter() {
+            let token_owner_id =
+                self.tokens.owner_by_id.get(token_id).unwrap_or_else(|| env::panic_str("Token not found"));
+            require!(token_owner_id == owner_id, "Predecessor must be token owner.");
+
+            let mut approved_account_ids = approvals_by_id.get(token_id).unwrap_or_default();
+            let approval_id: u64 = next_approval_id_by_id.get(token_id).unwrap_or(1u64);
+            // replacing an approval of the same account uses no storage, as in nft_approve
+            if approved_account_ids.insert(account_id.clone(), approval_id).is_none() {
+                storage_used += account_id.as_str().len() as u64 + 4 + 8;
+            }
+            approvals_by_id.insert(token_id, &approved_account_ids);
+            next_approval_id_by_id.insert(token_id, &(approval_id + 1));
+            approval_ids.push(approval_id);
+        }
+
+        let required_cost = env::storage_byte_cost().saturating_mul(storage_used.into());
+        let attached_deposit = env::attached_deposit();
+        require!(
+            required_cost <= attached_deposit,
+            format!("Must attach {} yoctoNEAR to cover storage", required_cost)
+        );
+        let refund = attached_deposit.saturating_sub(required_cost);
+        if refund.as_yoctonear() > 1 {
+            Promise::new(owner_id.clone()).transfer(refund);
+        }
+
+        msg.map(|msg| {
+            ext_nft_batch_approval_receiver::ext(account_id)
+                .with_static_gas(env::prepaid_gas().saturating_sub(GAS_FOR_NFT_BATCH_APPROVE))
+                .nft_on_batch_approve(token_ids, approval_ids, owner_id, msg)
+        })
+    }
+}
+
 #[near_bindgen]
 impl NonFungibleTokenEnumeration for Contract {
     fn nft_total_supply(&self) -> U128 {